@@ -0,0 +1,222 @@
+//! The `control_socket`: a Unix socket that takes one command per line, for
+//! scripts that query or switch the mode, e.g. when a game is launched.
+//!
+//! ```text
+//! mode get          -> the active mode
+//! mode set gaming   -> ok
+//! reload            -> ok
+//! explain KEY_J     -> what pressing KEY_J would do now
+//! ```
+//!
+//! Failures, and lines that aren't commands, are answered with `error: ...`.
+
+use crate::mapping::KeyCode;
+use anyhow::{Context, Result, bail};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    GetMode,
+    SetMode(String),
+    /// Re-read the config file, like SIGHUP.
+    Reload,
+    /// Which mapping a press of the key would resolve to, given the active
+    /// mode and the keys held right now.
+    Explain(KeyCode),
+}
+
+impl FromStr for ControlCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["mode", "get"] => Ok(Self::GetMode),
+            ["mode", "set", mode] => Ok(Self::SetMode(mode.to_string())),
+            ["mode", "set"] => bail!("`mode set` needs a mode name"),
+            ["reload"] => Ok(Self::Reload),
+            ["explain", key] => Ok(Self::Explain(crate::mapping::parse_key(key)?)),
+            ["explain"] => bail!("`explain` needs a key name"),
+            [] => bail!("empty command"),
+            _ => bail!("unknown command `{}`", words.join(" ")),
+        }
+    }
+}
+
+/// A command from a client, and where its one line answer goes.
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply: mpsc::Sender<String>,
+}
+
+/// The bound socket. Commands are answered by the event loop, which takes them
+/// from `requests` between input events, so they never race with remapping.
+pub struct ControlSocket {
+    path: PathBuf,
+    pub requests: mpsc::Receiver<ControlRequest>,
+}
+
+impl ControlSocket {
+    /// Binds `path`, replacing a socket left behind by an earlier run, and
+    /// accepts clients on a background thread. Each client gets a thread of its
+    /// own, so one that connects and says nothing doesn't hold up the others.
+    pub fn bind(path: &Path) -> Result<Self> {
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+        }
+        let listener =
+            UnixListener::bind(path).with_context(|| format!("binding {}", path.display()))?;
+        // Whoever can connect can switch modes, so only our own user may.
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("restricting {}", path.display()))?;
+        let (sender, requests) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let sender = sender.clone();
+                            let spawned = std::thread::Builder::new()
+                                .name("control client".to_string())
+                                .spawn(move || {
+                                    if let Err(err) = serve_client(stream, &sender) {
+                                        log::warn!("control client: {err:#}");
+                                    }
+                                });
+                            if let Err(err) = spawned {
+                                log::warn!("starting a control client thread: {err}");
+                            }
+                        },
+                        Err(err) => log::warn!("accepting control client: {err}"),
+                    }
+                }
+            })
+            .context("starting the control socket thread")?;
+        log::info!("accepting commands on {}", path.display());
+        Ok(Self { path: path.to_path_buf(), requests })
+    }
+
+    pub fn remove(&self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("removing {}: {err}", self.path.display());
+        }
+    }
+}
+
+fn serve_client(stream: UnixStream, sender: &mpsc::Sender<ControlRequest>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let answer = match line.parse() {
+            Err(err) => format!("error: {err}"),
+            Ok(command) => {
+                let (reply, answer) = mpsc::channel();
+                if sender
+                    .send(ControlRequest { command, reply })
+                    .is_err()
+                {
+                    bail!("the event loop has stopped");
+                }
+                answer
+                    .recv()
+                    .context("the event loop dropped the command")?
+            },
+        };
+        writeln!(writer, "{answer}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            "mode get"
+                .parse::<ControlCommand>()
+                .unwrap(),
+            ControlCommand::GetMode
+        );
+        assert_eq!(
+            "  mode   set gaming \n"
+                .parse::<ControlCommand>()
+                .unwrap(),
+            ControlCommand::SetMode("gaming".to_string())
+        );
+        assert_eq!(
+            "reload"
+                .parse::<ControlCommand>()
+                .unwrap(),
+            ControlCommand::Reload
+        );
+        assert_eq!(
+            "explain KEY_J"
+                .parse::<ControlCommand>()
+                .unwrap(),
+            ControlCommand::Explain(KeyCode::KEY_J)
+        );
+        assert_eq!(
+            "explain Escape"
+                .parse::<ControlCommand>()
+                .unwrap(),
+            ControlCommand::Explain(KeyCode::KEY_ESC)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let error = |line: &str| {
+            line.parse::<ControlCommand>()
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error(""), "empty command");
+        assert_eq!(error("mode set"), "`mode set` needs a mode name");
+        assert_eq!(error("mode set a b"), "unknown command `mode set a b`");
+        assert_eq!(error("mode"), "unknown command `mode`");
+        assert_eq!(error("reload now"), "unknown command `reload now`");
+        assert_eq!(error("MODE GET"), "unknown command `MODE GET`");
+        assert_eq!(error("explain"), "`explain` needs a key name");
+        assert_eq!(error("explain KEY_J KEY_K"), "unknown command `explain KEY_J KEY_K`");
+        assert!(error("explain KEY_NOPE").starts_with("Invalid key `KEY_NOPE`"));
+    }
+
+    #[test]
+    fn silent_client_doesnt_block_others() {
+        let path = std::env::temp_dir().join(format!("evremap-control-{}", std::process::id()));
+        let socket = ControlSocket::bind(&path).unwrap();
+        let mode = std::fs::metadata(&path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let _silent = UnixStream::connect(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        writeln!(client, "mode get").unwrap();
+
+        let request = socket
+            .requests
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("the second client was served");
+        assert_eq!(request.command, ControlCommand::GetMode);
+        request
+            .reply
+            .send("nav".to_string())
+            .unwrap();
+        let mut answer = String::new();
+        BufReader::new(client)
+            .read_line(&mut answer)
+            .unwrap();
+        assert_eq!(answer, "nav\n");
+        socket.remove();
+    }
+}
@@ -1,12 +1,58 @@
 use anyhow::{Context, Result, bail};
+use evdev_rs::enums::EventType;
 use evdev_rs::{Device, DeviceWrapper};
-use std::path::PathBuf;
+use regex::Regex;
+use serde::Serialize;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+/// How long `--probe` waits for a key on one of the candidate devices.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// `EV_KEY` from linux/input-event-codes.h, for the raw events read while probing.
+const EV_KEY: u16 = 0x01;
+
+thread_local! {
+    static CURRENT_DEVICE: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Names the device this thread remaps, so that log lines can say which one
+/// they are about when several are remapped at once.
+pub fn set_current_device(name: &str) {
+    CURRENT_DEVICE.with_borrow_mut(|current| *current = Some(name.to_string()));
+}
+
+pub fn current_device() -> Option<String> {
+    CURRENT_DEVICE.with_borrow(Clone::clone)
+}
+
+/// How the device to remap is identified.
 #[derive(Debug, Clone)]
+pub enum DeviceMatch {
+    Name(String),
+    NameRegex(Regex),
+    Ids { vendor: u16, product: u16 },
+}
+
+impl DeviceMatch {
+    pub fn find(&self, phys: Option<&str>, probe: bool) -> Result<DeviceInfo> {
+        match self {
+            Self::Name(name) => DeviceInfo::with_name(name, phys, probe),
+            Self::NameRegex(regex) => DeviceInfo::with_name_regex(regex, phys, probe),
+            Self::Ids { vendor, product } => DeviceInfo::with_ids(*vendor, *product, phys, probe),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct DeviceInfo {
     pub name: String,
     pub path: PathBuf,
     pub phys: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// The `EV_*` types the device reports, such as `EV_KEY` and `EV_REL`.
+    pub event_types: Vec<String>,
 }
 
 impl DeviceInfo {
@@ -18,11 +64,20 @@ impl DeviceInfo {
         Ok(Self {
             name: input.name().unwrap_or("").to_string(),
             phys: input.phys().unwrap_or("").to_string(),
+            vendor_id: input.vendor_id(),
+            product_id: input.product_id(),
+            event_types: EventType::EV_SYN
+                .iter()
+                .filter(|t| input.has(*t))
+                .map(|t| t.to_string())
+                .collect(),
             path,
         })
     }
 
-    pub fn with_name(name: &str, phys: Option<&str>) -> Result<Self> {
+    /// With `probe`, an ambiguous name is resolved by asking the user to press a
+    /// key and picking the device it came from.
+    pub fn with_name(name: &str, phys: Option<&str>, probe: bool) -> Result<Self> {
         let mut devices = Self::obtain_device_list()?;
 
         if let Some(phys) = phys {
@@ -37,7 +92,7 @@ impl DeviceInfo {
             }
         }
 
-        let mut devices_with_name: Vec<_> = devices
+        let devices_with_name: Vec<_> = devices
             .into_iter()
             .filter(|item| item.name == name)
             .collect();
@@ -46,20 +101,70 @@ impl DeviceInfo {
             bail!("No device found with name `{}`", name);
         }
 
-        if devices_with_name.len() > 1 {
-            log::warn!("The following devices match name `{name}`:");
-            for dev in &devices_with_name {
+        Self::choose(devices_with_name, &format!("name `{name}`"), probe)
+    }
+
+    /// For names that embed a firmware revision or part of a serial number.
+    /// Of several matches, the first in name order is used, unless `probe`.
+    pub fn with_name_regex(regex: &Regex, phys: Option<&str>, probe: bool) -> Result<Self> {
+        let candidates = select_by_name_regex(Self::obtain_device_list()?, regex, phys);
+        let what = format!("name regex `{regex}`");
+        if candidates.is_empty() {
+            match phys {
+                Some(phys) => bail!("No device found with {what} and phys=`{phys}`"),
+                None => bail!("No device found with {what}"),
+            }
+        }
+        Self::choose(candidates, &what, probe)
+    }
+
+    /// Matches on the USB/Bluetooth vendor and product IDs, which stay the same
+    /// when the name doesn't. `phys` narrows down several identical devices.
+    pub fn with_ids(vendor: u16, product: u16, phys: Option<&str>, probe: bool) -> Result<Self> {
+        let candidates = select_by_ids(Self::obtain_device_list()?, vendor, product, phys);
+        let what = format!("vendor {vendor:04x} product {product:04x}");
+        if candidates.is_empty() {
+            match phys {
+                Some(phys) => bail!("No device found with {what} and phys=`{phys}`"),
+                None => bail!("No device found with {what}"),
+            }
+        }
+        Self::choose(candidates, &what, probe)
+    }
+
+    /// Picks one of several devices that match `what`: by probing if asked to,
+    /// otherwise the first one, with a hint about `phys`.
+    fn choose(mut candidates: Vec<DeviceInfo>, what: &str, probe: bool) -> Result<Self> {
+        if candidates.len() > 1 && probe {
+            log::warn!(
+                "{} devices match {what}; press and release a key on the one to remap (waiting \
+                 {PROBE_TIMEOUT:?})",
+                candidates.len()
+            );
+            match probe_devices(&candidates, PROBE_TIMEOUT)? {
+                Some(idx) => {
+                    let dev = candidates.remove(idx);
+                    log::info!("Selected {} (phys `{}`)", dev.path.display(), dev.phys);
+                    return Ok(dev);
+                },
+                None => log::warn!("No key was pressed on any of them"),
+            }
+        }
+
+        if candidates.len() > 1 {
+            log::warn!("The following devices match {what}:");
+            for dev in &candidates {
                 log::warn!("{dev:?}");
             }
             log::warn!(
                 "evremap will use the first entry. If you want to use one of the others, add the \
                  corresponding phys value to your configuration, for example, `phys = \"{}\"` for \
                  the second entry in the list.",
-                devices_with_name[1].phys
+                candidates[1].phys
             );
         }
 
-        Ok(devices_with_name.remove(0))
+        Ok(candidates.remove(0))
     }
 
     fn obtain_device_list() -> Result<Vec<DeviceInfo>> {
@@ -97,7 +202,127 @@ impl DeviceInfo {
     }
 }
 
-fn event_number_from_path(path: &PathBuf) -> u32 {
+fn select_by_ids(
+    devices: Vec<DeviceInfo>,
+    vendor: u16,
+    product: u16,
+    phys: Option<&str>,
+) -> Vec<DeviceInfo> {
+    devices
+        .into_iter()
+        .filter(|dev| dev.vendor_id == vendor && dev.product_id == product)
+        .filter(|dev| phys.is_none_or(|phys| dev.phys == phys))
+        .collect()
+}
+
+fn select_by_name_regex(
+    devices: Vec<DeviceInfo>,
+    regex: &Regex,
+    phys: Option<&str>,
+) -> Vec<DeviceInfo> {
+    devices
+        .into_iter()
+        .filter(|dev| regex.is_match(&dev.name))
+        .filter(|dev| phys.is_none_or(|phys| dev.phys == phys))
+        .collect()
+}
+
+/// Parses a 16 bit ID as printed by `lsusb` and `list-devices`: hex, with or
+/// without a `0x` prefix.
+pub fn parse_hex_id(s: &str) -> Result<u16> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).with_context(|| format!("`{s}` is not a 16 bit hex ID"))
+}
+
+/// Watches `devices`, without grabbing them, and returns the index of the first
+/// one to report a key release within `timeout`. Waiting for the release rather
+/// than the press means that the key is up again before the device is grabbed.
+/// Devices that hang up or fail, e.g. because they were unplugged, are dropped
+/// from the watch rather than polled again.
+fn probe_devices(devices: &[DeviceInfo], timeout: Duration) -> Result<Option<usize>> {
+    let files = devices
+        .iter()
+        .map(|dev| {
+            std::fs::File::open(&dev.path)
+                .with_context(|| format!("opening {}", dev.path.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut pfds: Vec<libc::pollfd> = files
+        .iter()
+        .map(|f| libc::pollfd { fd: f.as_raw_fd(), events: libc::POLLIN, revents: 0 })
+        .collect();
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        let rc = unsafe {
+            libc::poll(
+                pfds.as_mut_ptr(),
+                pfds.len() as libc::nfds_t,
+                remaining.as_millis() as libc::c_int,
+            )
+        };
+        if rc < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err).context("polling candidate devices");
+        }
+
+        let mut reads = vec![];
+        for (idx, pfd) in pfds.iter_mut().enumerate() {
+            if pfd.revents & libc::POLLIN != 0 {
+                let mut events = [unsafe { std::mem::zeroed::<libc::input_event>() }; 64];
+                let n = unsafe {
+                    libc::read(pfd.fd, events.as_mut_ptr().cast(), std::mem::size_of_val(&events))
+                };
+                if n > 0 {
+                    let count = n as usize / std::mem::size_of::<libc::input_event>();
+                    reads.push((idx, events[..count].to_vec()));
+                }
+            }
+            if pfd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0 {
+                log::debug!("{} went away while probing", devices[idx].path.display());
+                // poll skips negative descriptors.
+                pfd.fd = -1;
+            }
+        }
+        if let Some(idx) = first_key_release(
+            reads
+                .iter()
+                .map(|(idx, events)| (*idx, &events[..])),
+        ) {
+            return Ok(Some(idx));
+        }
+        if pfds.iter().all(|pfd| pfd.fd < 0) {
+            return Ok(None);
+        }
+    }
+}
+
+/// The index of the first device whose events, read in one round, include a
+/// key release.
+fn first_key_release<'a>(
+    reads: impl IntoIterator<Item = (usize, &'a [libc::input_event])>,
+) -> Option<usize> {
+    reads
+        .into_iter()
+        .find(|(_, events)| {
+            events
+                .iter()
+                .any(|ev| ev.type_ == EV_KEY && ev.value == 0)
+        })
+        .map(|(idx, _)| idx)
+}
+
+fn event_number_from_path(path: &Path) -> u32 {
     match path.to_str() {
         Some(s) => match s.rfind("event") {
             Some(idx) => s[idx + 5..].parse().unwrap_or(0),
@@ -107,13 +332,240 @@ fn event_number_from_path(path: &PathBuf) -> u32 {
     }
 }
 
-pub fn list_devices() -> Result<()> {
-    let devices = DeviceInfo::obtain_device_list()?;
-    for item in &devices {
-        println!("Name: {}", item.name);
-        println!("Path: {}", item.path.display());
-        println!("Phys: {}", item.phys);
-        println!();
+/// With `json`, prints a JSON array of the devices for other programs.
+/// With `filter`, only devices whose name contains it, ignoring case, are listed.
+pub fn list_devices(json: bool, filter: Option<&str>) -> Result<()> {
+    let devices = filter_by_name(DeviceInfo::obtain_device_list()?, filter);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices).context("describing the devices")?);
+        return Ok(());
+    }
+    for row in device_table(&devices) {
+        println!("{row}");
     }
     Ok(())
 }
+
+fn filter_by_name(devices: Vec<DeviceInfo>, filter: Option<&str>) -> Vec<DeviceInfo> {
+    let Some(filter) = filter.map(str::to_lowercase) else {
+        return devices;
+    };
+    devices
+        .into_iter()
+        .filter(|dev| {
+            dev.name
+                .to_lowercase()
+                .contains(&filter)
+        })
+        .collect()
+}
+
+/// A header and one line per device, in aligned columns, so that devices of
+/// the same name can be told apart by their `phys` and path at a glance.
+fn device_table(devices: &[DeviceInfo]) -> Vec<String> {
+    let header = ["NAME", "PHYS", "PATH", "ID", "EVENTS"].map(String::from);
+    let rows: Vec<[String; 5]> = std::iter::once(header)
+        .chain(devices.iter().map(|dev| {
+            [
+                dev.name.clone(),
+                dev.phys.clone(),
+                dev.path.display().to_string(),
+                format!("{:04x}:{:04x}", dev.vendor_id, dev.product_id),
+                dev.event_types.join(" "),
+            ]
+        }))
+        .collect();
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            let mut line = String::new();
+            for (width, cell) in widths.iter().zip(row) {
+                line.push_str(&format!("{cell:<width$}  "));
+            }
+            line.push_str(&row[4]);
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_event(type_: u16, code: u16, value: i32) -> libc::input_event {
+        libc::input_event { time: libc::timeval { tv_sec: 0, tv_usec: 0 }, type_, code, value }
+    }
+
+    #[test]
+    fn probe_picks_the_first_device_with_a_key_release() {
+        const EV_SYN: u16 = 0x00;
+        const EV_REL: u16 = 0x02;
+        const KEY_A: u16 = 30;
+        let mouse = [
+            raw_event(EV_REL, 0, -1),
+            raw_event(EV_SYN, 0, 0),
+        ];
+        let press = [
+            raw_event(EV_KEY, KEY_A, 1),
+            raw_event(EV_SYN, 0, 0),
+        ];
+        let release = [
+            raw_event(EV_KEY, KEY_A, 0),
+            raw_event(EV_SYN, 0, 0),
+        ];
+        let repeat = [raw_event(EV_KEY, KEY_A, 2)];
+
+        assert_eq!(first_key_release([]), None);
+        assert_eq!(first_key_release([(0, &mouse[..]), (1, &press[..])]), None);
+        assert_eq!(first_key_release([(2, &repeat[..])]), None);
+        assert_eq!(
+            first_key_release([
+                (0, &press[..]),
+                (3, &release[..]),
+                (4, &release[..])
+            ]),
+            Some(3)
+        );
+        // A syn with value 0 isn't a release.
+        assert_eq!(first_key_release([(1, &mouse[1..])]), None);
+    }
+
+    fn dev(phys: &str, vendor_id: u16, product_id: u16) -> DeviceInfo {
+        DeviceInfo {
+            name: "Keyboard".to_string(),
+            path: PathBuf::from(format!("/dev/input/event-{phys}")),
+            phys: phys.to_string(),
+            vendor_id,
+            product_id,
+            event_types: vec!["EV_SYN".to_string(), "EV_KEY".to_string()],
+        }
+    }
+
+    #[test]
+    fn devices_as_json() {
+        let json = serde_json::to_string_pretty(&[dev("usb-1", 0x046d, 0xc52b)]).unwrap();
+        assert_eq!(
+            json,
+            r#"[
+  {
+    "name": "Keyboard",
+    "path": "/dev/input/event-usb-1",
+    "phys": "usb-1",
+    "vendor_id": 1133,
+    "product_id": 50475,
+    "event_types": [
+      "EV_SYN",
+      "EV_KEY"
+    ]
+  }
+]"#
+        );
+        assert_eq!(serde_json::to_string_pretty::<[DeviceInfo]>(&[]).unwrap(), "[]");
+    }
+
+    #[test]
+    fn device_table_aligns_columns() {
+        let at = |name: &str, phys: &str, path: &str| DeviceInfo {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+            ..dev(phys, 0x046d, 0xc52b)
+        };
+        let devices = [
+            at("Keyboard Ünicode", "usb-1", "/dev/input/event3"),
+            at("Keyboard", "usb-0000:07:00.3-2.1.1/input0", "/dev/input/event12"),
+        ];
+        assert_eq!(device_table(&devices), [
+            "NAME              PHYS                           PATH                ID         \
+             EVENTS",
+            "Keyboard Ünicode  usb-1                          /dev/input/event3   046d:c52b  \
+             EV_SYN EV_KEY",
+            "Keyboard          usb-0000:07:00.3-2.1.1/input0  /dev/input/event12  046d:c52b  \
+             EV_SYN EV_KEY",
+        ]);
+        assert_eq!(device_table(&[]), ["NAME  PHYS  PATH  ID  EVENTS"]);
+    }
+
+    #[test]
+    fn filters_devices_by_name() {
+        let named = |name: &str| DeviceInfo { name: name.to_string(), ..dev(name, 0, 0) };
+        let devices = vec![
+            named("Keychron K2"),
+            named("Logitech USB Receiver"),
+            named("keychron K6"),
+        ];
+        let names = |found: Vec<DeviceInfo>| -> Vec<String> {
+            found
+                .into_iter()
+                .map(|d| d.name)
+                .collect()
+        };
+        assert_eq!(names(filter_by_name(devices.clone(), Some("KEYCHRON"))), [
+            "Keychron K2",
+            "keychron K6"
+        ]);
+        assert_eq!(names(filter_by_name(devices.clone(), None)).len(), 3);
+        assert!(filter_by_name(devices, Some("Razer")).is_empty());
+    }
+
+    #[test]
+    fn selects_by_ids_then_phys() {
+        let devices = vec![
+            dev("a", 0x046d, 0xc52b),
+            dev("b", 0x046d, 0xc534),
+            dev("c", 0x046d, 0xc52b),
+        ];
+
+        let both = select_by_ids(devices.clone(), 0x046d, 0xc52b, None);
+        let phys: Vec<_> = both
+            .iter()
+            .map(|d| d.phys.as_str())
+            .collect();
+        assert_eq!(phys, ["a", "c"]);
+
+        let one = select_by_ids(devices.clone(), 0x046d, 0xc52b, Some("c"));
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].phys, "c");
+
+        assert!(select_by_ids(devices, 0x1234, 0xc52b, None).is_empty());
+    }
+
+    #[test]
+    fn selects_by_name_regex_then_phys() {
+        let named =
+            |name: &str, phys: &str| DeviceInfo { name: name.to_string(), ..dev(phys, 0, 0) };
+        let devices = vec![
+            named("Keychron K2 (fw 1.04)", "usb-1"),
+            named("Keychron K2 (fw 1.05)", "usb-2"),
+            named("Keychron K6", "usb-3"),
+            named("Logitech USB Receiver", "usb-4"),
+        ];
+        let phys = |found: Vec<DeviceInfo>| -> Vec<String> {
+            found
+                .into_iter()
+                .map(|d| d.phys)
+                .collect()
+        };
+
+        let k2 = Regex::new(r"^Keychron K2 \(fw [\d.]+\)$").unwrap();
+        assert_eq!(phys(select_by_name_regex(devices.clone(), &k2, None)), ["usb-1", "usb-2"]);
+        assert_eq!(phys(select_by_name_regex(devices.clone(), &k2, Some("usb-2"))), ["usb-2"]);
+        assert!(select_by_name_regex(devices.clone(), &k2, Some("usb-3")).is_empty());
+
+        let unanchored = Regex::new("Keychron").unwrap();
+        assert_eq!(select_by_name_regex(devices.clone(), &unanchored, None).len(), 3);
+        assert!(select_by_name_regex(devices, &Regex::new("Razer").unwrap(), None).is_empty());
+    }
+
+    #[test]
+    fn hex_ids() {
+        assert_eq!(parse_hex_id("046d").unwrap(), 0x046d);
+        assert_eq!(parse_hex_id("0xC52B").unwrap(), 0xc52b);
+        assert!(parse_hex_id("10000").is_err());
+        assert!(parse_hex_id("zz").is_err());
+    }
+}
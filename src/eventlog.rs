@@ -0,0 +1,120 @@
+//! The plain-text event format written by `record-events` and read by `replay`.
+//!
+//! One event per line, `SECONDS.MICROSECONDS TYPE CODE VALUE`:
+//!
+//! ```text
+//! 1712345678.000123 EV_KEY KEY_CAPSLOCK 1
+//! 1712345678.000123 EV_SYN SYN_REPORT 0
+//! ```
+//!
+//! Codes without a name are written as numbers. Blank lines and lines
+//! starting with `#` are skipped, so a recording can be trimmed and annotated
+//! by hand.
+
+use crate::remapper::EventSink;
+use anyhow::{Context, Result, anyhow, bail};
+use evdev_rs::enums::{EventCode, EventType};
+use evdev_rs::util::{event_code_to_int, int_to_event_code};
+use evdev_rs::{InputEvent, TimeVal};
+use std::io::Write;
+
+pub fn write_event(out: &mut impl Write, event: &InputEvent) -> Result<()> {
+    let (ev_type, code) = event_code_to_int(&event.event_code);
+    let name = event.event_code.to_string();
+    let type_name = event
+        .event_type()
+        .map(|t| t.to_string())
+        .unwrap_or_else(|| ev_type.to_string());
+    write!(out, "{}.{:06} {type_name} ", event.time.tv_sec, event.time.tv_usec)?;
+    if name.is_empty() {
+        write!(out, "{code}")?;
+    } else {
+        write!(out, "{name}")?;
+    }
+    writeln!(out, " {}", event.value)?;
+    Ok(())
+}
+
+/// Writes each event it is given in this format; `replay` uses it to print
+/// what a recording turns into.
+pub struct EventWriter<W: Write>(pub W);
+
+impl<W: Write> EventSink for EventWriter<W> {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        write_event(&mut self.0, event)
+    }
+}
+
+/// Returns `None` for blank and comment lines.
+fn parse_event(line: &str) -> Result<Option<InputEvent>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [time, ev_type, code, value] = fields[..] else {
+        bail!("expected `SECONDS.MICROSECONDS TYPE CODE VALUE`, got `{line}`");
+    };
+    let (sec, usec) = time
+        .split_once('.')
+        .ok_or_else(|| anyhow!("timestamp `{time}` has no `.`"))?;
+    let time = TimeVal::new(sec.parse()?, usec.parse()?);
+    let ev_type =
+        EventType::from_str(ev_type).ok_or_else(|| anyhow!("unknown event type `{ev_type}`"))?;
+    let event_code = match EventCode::from_str(&ev_type, code) {
+        Some(event_code) => event_code,
+        None => {
+            let code: u32 = code
+                .parse()
+                .map_err(|_| anyhow!("unknown {ev_type} code `{code}`"))?;
+            int_to_event_code(ev_type as u32, code)
+        },
+    };
+    Ok(Some(InputEvent::new(&time, &event_code, value.parse()?)))
+}
+
+/// Parses a whole recording; errors name the offending line.
+pub fn parse_events(text: &str) -> Result<Vec<InputEvent>> {
+    let mut events = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        if let Some(event) = parse_event(line).with_context(|| format!("line {}", idx + 1))? {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::enums::{EV_KEY, EV_REL, EV_SYN};
+
+    #[test]
+    fn round_trip() {
+        let events = [
+            InputEvent::new(&TimeVal::new(12, 345), &EventCode::EV_KEY(EV_KEY::KEY_CAPSLOCK), 1),
+            InputEvent::new(&TimeVal::new(12, 345), &EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+            InputEvent::new(&TimeVal::new(13, 0), &EventCode::EV_REL(EV_REL::REL_X), -4),
+        ];
+        let mut buf = Vec::new();
+        for event in &events {
+            write_event(&mut buf, event).unwrap();
+        }
+        let text = String::from_utf8(buf).unwrap();
+        assert_eq!(text.lines().next(), Some("12.000345 EV_KEY KEY_CAPSLOCK 1"));
+
+        let parsed = parse_events(&format!("# trimmed by hand\n\n{text}")).unwrap();
+        assert_eq!(parsed.len(), events.len());
+        for (a, b) in parsed.iter().zip(&events) {
+            assert_eq!(a.time, b.time);
+            assert_eq!(a.event_code, b.event_code);
+            assert_eq!(a.value, b.value);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let err = parse_events("1.0 EV_KEY KEY_A 1\n1.0 EV_KEY KEY_NOPE 1\n").unwrap_err();
+        assert_eq!(format!("{err:#}"), "line 2: unknown EV_KEY code `KEY_NOPE`");
+    }
+}
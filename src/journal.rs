@@ -0,0 +1,146 @@
+//! A `log` backend that speaks the native systemd-journald protocol, so that
+//! the key-value pairs attached to log records (`mode`, `key`, `type`) become
+//! journal fields that can be matched with e.g. `journalctl MODE=nav`.
+
+use anyhow::{Context, Result};
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+use std::os::unix::net::UnixDatagram;
+
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// A socket for sending records to the journal listening on `path`.
+pub fn connect(path: &str) -> Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .connect(path)
+        .with_context(|| format!("connecting to {path}"))?;
+    Ok(socket)
+}
+
+pub struct JournaldLogger {
+    socket: UnixDatagram,
+    /// Reused for its `EVREMAP_LOG` filtering; never asked to write anything.
+    filter: env_logger::Logger,
+}
+
+impl JournaldLogger {
+    pub fn new(socket: UnixDatagram, filter: env_logger::Logger) -> Self {
+        Self { socket, filter }
+    }
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        // Logging must never take the remapper down; a lost line is preferable.
+        let _ = self.socket.send(&format_record(record));
+    }
+
+    fn flush(&self) {
+    }
+}
+
+fn syslog_priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Appends one field. Values containing a newline must use the length-prefixed form.
+fn push_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+/// Journal field names are upper case ASCII letters, digits and underscores,
+/// and may not start with an underscore (those are reserved for journald).
+fn field_name(key: &str) -> Option<String> {
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+        .then_some(name)
+}
+
+struct FieldVisitor<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for FieldVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        if let Some(name) = field_name(key.as_str()) {
+            push_field(self.0, &name, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+fn format_record(record: &Record) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_field(&mut buf, "MESSAGE", &record.args().to_string());
+    push_field(&mut buf, "PRIORITY", &syslog_priority(record.level()).to_string());
+    push_field(&mut buf, "SYSLOG_IDENTIFIER", "evremap");
+    push_field(&mut buf, "TARGET", record.target());
+    if let Some(device) = crate::deviceinfo::current_device() {
+        push_field(&mut buf, "DEVICE", &device);
+    }
+    let _ = record
+        .key_values()
+        .visit(&mut FieldVisitor(&mut buf));
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_fields() {
+        let record = Record::builder()
+            .args(format_args!("switched to mode nav"))
+            .level(Level::Info)
+            .target("evremap::remapper")
+            .key_values(&[("mode", "nav"), ("type", "EV_KEY")])
+            .build();
+        let text = String::from_utf8(format_record(&record)).unwrap();
+        let fields: Vec<&str> = text.lines().collect();
+        assert_eq!(fields, [
+            "MESSAGE=switched to mode nav",
+            "PRIORITY=6",
+            "SYSLOG_IDENTIFIER=evremap",
+            "TARGET=evremap::remapper",
+            "MODE=nav",
+            "TYPE=EV_KEY",
+        ]);
+    }
+
+    #[test]
+    fn multiline_values_are_length_prefixed() {
+        let mut buf = Vec::new();
+        push_field(&mut buf, "MESSAGE", "a\nb");
+        assert_eq!(buf, b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+    }
+
+    #[test]
+    fn field_names() {
+        assert_eq!(field_name("mode").as_deref(), Some("MODE"));
+        assert_eq!(field_name("orphan-releases").as_deref(), Some("ORPHAN_RELEASES"));
+        assert_eq!(field_name("_pid"), None);
+    }
+}
@@ -0,0 +1,177 @@
+//! Translation of X11/Wayland keysym names (as used by `xmodmap` and `setxkbmap`)
+//! into evdev key codes.
+//!
+//! evremap operates below the keyboard layout, so this table is layout
+//! independent: it names the key that produces the keysym on a US QWERTY
+//! layout. Keysyms that only exist as the shifted level of some key (such as
+//! `exclam`) have no evdev equivalent of their own.
+
+use crate::mapping::{EventCode, EventType, KeyCode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keysym {
+    Key(KeyCode),
+    /// A known keysym that has no dedicated evdev key; holds the chord that produces it.
+    Shifted(&'static str),
+}
+
+const KEYSYMS: &[(&str, KeyCode)] = &[
+    ("Escape", KeyCode::KEY_ESC),
+    ("Return", KeyCode::KEY_ENTER),
+    ("Tab", KeyCode::KEY_TAB),
+    ("BackSpace", KeyCode::KEY_BACKSPACE),
+    ("space", KeyCode::KEY_SPACE),
+    ("Delete", KeyCode::KEY_DELETE),
+    ("Insert", KeyCode::KEY_INSERT),
+    ("Home", KeyCode::KEY_HOME),
+    ("End", KeyCode::KEY_END),
+    ("Prior", KeyCode::KEY_PAGEUP),
+    ("Page_Up", KeyCode::KEY_PAGEUP),
+    ("Next", KeyCode::KEY_PAGEDOWN),
+    ("Page_Down", KeyCode::KEY_PAGEDOWN),
+    ("Left", KeyCode::KEY_LEFT),
+    ("Right", KeyCode::KEY_RIGHT),
+    ("Up", KeyCode::KEY_UP),
+    ("Down", KeyCode::KEY_DOWN),
+    ("Print", KeyCode::KEY_SYSRQ),
+    ("Pause", KeyCode::KEY_PAUSE),
+    ("Menu", KeyCode::KEY_COMPOSE),
+    ("Control_L", KeyCode::KEY_LEFTCTRL),
+    ("Control_R", KeyCode::KEY_RIGHTCTRL),
+    ("Shift_L", KeyCode::KEY_LEFTSHIFT),
+    ("Shift_R", KeyCode::KEY_RIGHTSHIFT),
+    ("Alt_L", KeyCode::KEY_LEFTALT),
+    ("Alt_R", KeyCode::KEY_RIGHTALT),
+    ("ISO_Level3_Shift", KeyCode::KEY_RIGHTALT),
+    ("Meta_L", KeyCode::KEY_LEFTMETA),
+    ("Meta_R", KeyCode::KEY_RIGHTMETA),
+    ("Super_L", KeyCode::KEY_LEFTMETA),
+    ("Super_R", KeyCode::KEY_RIGHTMETA),
+    ("Caps_Lock", KeyCode::KEY_CAPSLOCK),
+    ("Num_Lock", KeyCode::KEY_NUMLOCK),
+    ("Scroll_Lock", KeyCode::KEY_SCROLLLOCK),
+    ("minus", KeyCode::KEY_MINUS),
+    ("equal", KeyCode::KEY_EQUAL),
+    ("bracketleft", KeyCode::KEY_LEFTBRACE),
+    ("bracketright", KeyCode::KEY_RIGHTBRACE),
+    ("semicolon", KeyCode::KEY_SEMICOLON),
+    ("apostrophe", KeyCode::KEY_APOSTROPHE),
+    ("grave", KeyCode::KEY_GRAVE),
+    ("backslash", KeyCode::KEY_BACKSLASH),
+    ("comma", KeyCode::KEY_COMMA),
+    ("period", KeyCode::KEY_DOT),
+    ("slash", KeyCode::KEY_SLASH),
+    ("KP_Enter", KeyCode::KEY_KPENTER),
+    ("KP_Add", KeyCode::KEY_KPPLUS),
+    ("KP_Subtract", KeyCode::KEY_KPMINUS),
+    ("KP_Multiply", KeyCode::KEY_KPASTERISK),
+    ("KP_Divide", KeyCode::KEY_KPSLASH),
+    ("KP_Decimal", KeyCode::KEY_KPDOT),
+    ("KP_0", KeyCode::KEY_KP0),
+    ("KP_1", KeyCode::KEY_KP1),
+    ("KP_2", KeyCode::KEY_KP2),
+    ("KP_3", KeyCode::KEY_KP3),
+    ("KP_4", KeyCode::KEY_KP4),
+    ("KP_5", KeyCode::KEY_KP5),
+    ("KP_6", KeyCode::KEY_KP6),
+    ("KP_7", KeyCode::KEY_KP7),
+    ("KP_8", KeyCode::KEY_KP8),
+    ("KP_9", KeyCode::KEY_KP9),
+    ("XF86AudioMute", KeyCode::KEY_MUTE),
+    ("XF86AudioLowerVolume", KeyCode::KEY_VOLUMEDOWN),
+    ("XF86AudioRaiseVolume", KeyCode::KEY_VOLUMEUP),
+    ("XF86AudioPlay", KeyCode::KEY_PLAYPAUSE),
+    ("XF86AudioStop", KeyCode::KEY_STOPCD),
+    ("XF86AudioPrev", KeyCode::KEY_PREVIOUSSONG),
+    ("XF86AudioNext", KeyCode::KEY_NEXTSONG),
+    ("XF86MonBrightnessUp", KeyCode::KEY_BRIGHTNESSUP),
+    ("XF86MonBrightnessDown", KeyCode::KEY_BRIGHTNESSDOWN),
+];
+
+const SHIFTED_KEYSYMS: &[(&str, &str)] = &[
+    ("exclam", "KEY_LEFTSHIFT + KEY_1"),
+    ("at", "KEY_LEFTSHIFT + KEY_2"),
+    ("numbersign", "KEY_LEFTSHIFT + KEY_3"),
+    ("dollar", "KEY_LEFTSHIFT + KEY_4"),
+    ("percent", "KEY_LEFTSHIFT + KEY_5"),
+    ("asciicircum", "KEY_LEFTSHIFT + KEY_6"),
+    ("ampersand", "KEY_LEFTSHIFT + KEY_7"),
+    ("asterisk", "KEY_LEFTSHIFT + KEY_8"),
+    ("parenleft", "KEY_LEFTSHIFT + KEY_9"),
+    ("parenright", "KEY_LEFTSHIFT + KEY_0"),
+    ("underscore", "KEY_LEFTSHIFT + KEY_MINUS"),
+    ("plus", "KEY_LEFTSHIFT + KEY_EQUAL"),
+    ("braceleft", "KEY_LEFTSHIFT + KEY_LEFTBRACE"),
+    ("braceright", "KEY_LEFTSHIFT + KEY_RIGHTBRACE"),
+    ("colon", "KEY_LEFTSHIFT + KEY_SEMICOLON"),
+    ("quotedbl", "KEY_LEFTSHIFT + KEY_APOSTROPHE"),
+    ("asciitilde", "KEY_LEFTSHIFT + KEY_GRAVE"),
+    ("bar", "KEY_LEFTSHIFT + KEY_BACKSLASH"),
+    ("less", "KEY_LEFTSHIFT + KEY_COMMA"),
+    ("greater", "KEY_LEFTSHIFT + KEY_DOT"),
+    ("question", "KEY_LEFTSHIFT + KEY_SLASH"),
+];
+
+fn key_by_name(name: &str) -> Option<KeyCode> {
+    match EventCode::from_str(&EventType::EV_KEY, name)? {
+        EventCode::EV_KEY(code) => Some(code),
+        _ => None,
+    }
+}
+
+/// Resolves a keysym name such as `Escape`, `Control_L`, `a` or `F5`.
+pub fn lookup(name: &str) -> Option<Keysym> {
+    if let Some((_, code)) = KEYSYMS
+        .iter()
+        .find(|(sym, _)| *sym == name)
+    {
+        return Some(Keysym::Key(*code));
+    }
+    if let Some((_, chord)) = SHIFTED_KEYSYMS
+        .iter()
+        .find(|(sym, _)| *sym == name)
+    {
+        return Some(Keysym::Shifted(chord));
+    }
+
+    // Letters (either case), digits and function keys share their evdev names.
+    let is_single_alnum = name.len() == 1
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric());
+    let is_function_key = name
+        .strip_prefix('F')
+        .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+    if is_single_alnum || is_function_key {
+        return key_by_name(&format!("KEY_{}", name.to_ascii_uppercase())).map(Keysym::Key);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_keysyms() {
+        assert_eq!(lookup("Escape"), Some(Keysym::Key(KeyCode::KEY_ESC)));
+        assert_eq!(lookup("Control_L"), Some(Keysym::Key(KeyCode::KEY_LEFTCTRL)));
+        assert_eq!(lookup("period"), Some(Keysym::Key(KeyCode::KEY_DOT)));
+    }
+
+    #[test]
+    fn letters_digits_and_function_keys() {
+        assert_eq!(lookup("a"), Some(Keysym::Key(KeyCode::KEY_A)));
+        assert_eq!(lookup("Z"), Some(Keysym::Key(KeyCode::KEY_Z)));
+        assert_eq!(lookup("7"), Some(Keysym::Key(KeyCode::KEY_7)));
+        assert_eq!(lookup("F11"), Some(Keysym::Key(KeyCode::KEY_F11)));
+        assert_eq!(lookup("F"), Some(Keysym::Key(KeyCode::KEY_F)));
+    }
+
+    #[test]
+    fn shifted_and_unknown_keysyms() {
+        assert_eq!(lookup("exclam"), Some(Keysym::Shifted("KEY_LEFTSHIFT + KEY_1")));
+        assert_eq!(lookup("F99"), None);
+        assert_eq!(lookup("NotAKeysym"), None);
+    }
+}
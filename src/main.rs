@@ -1,16 +1,26 @@
 #![feature(test)]
 
-use crate::deviceinfo::DeviceInfo;
+use crate::deviceinfo::{DeviceInfo, DeviceMatch};
 use crate::mapping::*;
 use crate::remapper::*;
 use anyhow::{Context, Result};
-use clap::{CommandFactory, Parser, Subcommand, ValueHint};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+mod control;
 mod deviceinfo;
+mod eventlog;
+mod journal;
+mod keysym;
 mod mapping;
+mod notify;
+mod recorder;
 mod remapper;
+mod scaffold;
+mod signals;
+mod state;
+mod status;
 
 #[derive(Debug, Parser)]
 #[command(
@@ -24,101 +34,425 @@ struct Cli {
 
     #[arg(value_name = "CONFIG-FILE", value_hint = ValueHint::FilePath)]
     config_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = LogFormat::Human,
+        help = "Where and how to write log messages"
+    )]
+    log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Plain text on stderr
+    Human,
+    /// Native journald records, with `mode`, `key` and `type` as queryable fields
+    Journald,
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    ListDevices,
+    ListDevices {
+        #[arg(
+            long,
+            help = "Print a JSON array instead, for other programs"
+        )]
+        json: bool,
 
-    ListKeys,
+        #[arg(
+            long,
+            help = "Only list devices whose name contains this, ignoring case"
+        )]
+        filter: Option<String>,
+    },
 
-    DebugEvents {
-        #[arg(long)]
-        device_name: String,
+    ListKeys {
+        #[arg(help = "Only list keys whose name starts with this, such as KEY_F")]
+        prefix: Option<String>,
 
-        #[arg(long)]
-        phys: Option<String>,
+        #[arg(
+            long,
+            help = "Only list keys whose name contains this, ignoring case"
+        )]
+        filter: Option<String>,
     },
 
     #[command(
         arg_required_else_help = true,
-        about = "Apply mappings from a TOML config to a device"
+        about = "Check a TOML config for mistakes without grabbing a device"
     )]
-    Remap {
-        #[arg(
-            value_name = "/path/to/config.toml",
-            value_hint = ValueHint::FilePath,
-            help = "Path to the remapping config (TOML). Required."
-        )]
+    Validate {
+        #[arg(value_name = "/path/to/config.toml", value_hint = ValueHint::FilePath)]
         config_file: PathBuf,
+    },
 
-        #[arg(short, long, default_value_t = 2.0)]
-        delay: f64,
+    #[command(
+        arg_required_else_help = true,
+        about = "Print the mappings a TOML config expands to, in the order the engine tries them"
+    )]
+    PrintConfig {
+        #[arg(value_name = "/path/to/config.toml", value_hint = ValueHint::AnyPath)]
+        config_file: PathBuf,
+    },
 
+    DebugEvents {
         #[arg(long)]
-        device_name: Option<String>,
+        device_name: String,
 
         #[arg(long)]
         phys: Option<String>,
+    },
+
+    #[command(about = "Write every event from a device to a text file, until interrupted")]
+    RecordEvents {
+        #[arg(long)]
+        device_name: String,
 
         #[arg(long)]
-        wait_for_device: bool,
+        phys: Option<String>,
+
+        #[arg(long, value_hint = ValueHint::FilePath)]
+        output: PathBuf,
     },
+
+    #[command(
+        about = "Print a starter config for a device, with a remap stub for each key pressed \
+                 while it watches"
+    )]
+    GenerateConfig {
+        #[arg(long)]
+        device_name: String,
+
+        #[arg(long)]
+        phys: Option<String>,
+
+        #[arg(long, default_value_t = 30)]
+        duration_secs: u64,
+    },
+
+    #[command(
+        arg_required_else_help = true,
+        about = "Run a recording from record-events through a config and print the output events"
+    )]
+    Replay {
+        #[arg(value_name = "/path/to/config.toml", value_hint = ValueHint::FilePath)]
+        config_file: PathBuf,
+
+        #[arg(value_name = "/path/to/events.txt", value_hint = ValueHint::FilePath)]
+        events: PathBuf,
+    },
+
+    #[command(
+        arg_required_else_help = true,
+        about = "Apply mappings from a TOML config to a device"
+    )]
+    Remap(RemapArgs),
+}
+
+#[derive(Debug, Args)]
+struct RemapArgs {
+    #[arg(
+        value_name = "/path/to/config.toml",
+        value_hint = ValueHint::FilePath,
+        required_unless_present = "config_dir",
+        help = "Path to the remapping config (TOML, or JSON if it ends in .json). Required \
+                unless --config-dir is given."
+    )]
+    config_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_hint = ValueHint::DirPath,
+        conflicts_with = "config_file",
+        help = "Read every *.toml file in this directory, in name order, as one config: lists \
+                such as [[remap]] are combined, and later files override settings"
+    )]
+    config_dir: Option<PathBuf>,
+
+    #[arg(short, long, default_value_t = 2.0)]
+    delay: f64,
+
+    #[arg(
+        long,
+        help = "Repeat to remap several devices; replaces the names from the config"
+    )]
+    device_name: Vec<String>,
+
+    #[arg(
+        long,
+        value_parser = parse_device_name_regex,
+        help = "Remap the first device whose name matches this regular expression; replaces the \
+                names from the config"
+    )]
+    device_name_regex: Option<regex::Regex>,
+
+    #[arg(
+        long,
+        value_parser = deviceinfo::parse_hex_id,
+        help = "USB vendor ID in hex, e.g. 046d; with --product-id, takes precedence over the name"
+    )]
+    vendor_id: Option<u16>,
+
+    #[arg(long, value_parser = deviceinfo::parse_hex_id, help = "USB product ID in hex, e.g. c52b")]
+    product_id: Option<u16>,
+
+    #[arg(long)]
+    phys: Option<String>,
+
+    #[arg(long)]
+    wait_for_device: bool,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "With --wait-for-device, give up after this many seconds; 0 waits forever"
+    )]
+    wait_timeout_secs: u64,
+
+    #[arg(
+        long,
+        help = "Print every mode transition to stderr, regardless of log level"
+    )]
+    watch_mode: bool,
+
+    #[arg(
+        long,
+        help = "Print every key event read and written to stderr, one line each, regardless of \
+                log level"
+    )]
+    log_events: bool,
+
+    #[arg(
+        long,
+        help = "If several devices match, wait up to 10s for a key press and remap the device it \
+                came from; otherwise fall back to the first match"
+    )]
+    probe: bool,
+
+    #[arg(
+        long,
+        help = "Don't take exclusive access to the device; the desktop sees both the original and \
+                the remapped events, which is only useful for debugging a config"
+    )]
+    no_grab: bool,
+
+    #[arg(
+        long,
+        help = "Read and remap the device, but print the output to stdout instead of creating a \
+                virtual device; combine with --no-grab to keep typing normally"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        value_hint = ValueHint::FilePath,
+        help = "Write the active mode to this file or named pipe whenever it changes; replaces \
+                status_path from the config"
+    )]
+    status_path: Option<PathBuf>,
+}
+
+impl RemapArgs {
+    /// The settings used by `evremap CONFIG-FILE` without a subcommand.
+    fn with_defaults(config_file: PathBuf) -> Self {
+        Self {
+            config_file: Some(config_file),
+            config_dir: None,
+            delay: 2.0,
+            device_name: Vec::new(),
+            device_name_regex: None,
+            vendor_id: None,
+            product_id: None,
+            phys: None,
+            wait_for_device: false,
+            wait_timeout_secs: 0,
+            watch_mode: false,
+            log_events: false,
+            probe: false,
+            no_grab: false,
+            dry_run: false,
+            status_path: None,
+        }
+    }
 }
 
-pub fn list_keys() -> Result<()> {
+/// All `EV_KEY` code names, sorted, without the `KEY_MAX`/`KEY_CNT` style bounds.
+fn collect_key_names() -> Vec<String> {
     let mut keys: Vec<String> = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
         .iter()
         .filter_map(|code| match code {
             EventCode::EV_KEY(_) => Some(format!("{code}")),
             _ => None,
         })
+        .filter(|name| !name.ends_with("_MAX") && !name.ends_with("_CNT"))
         .collect();
     keys.sort();
+    keys
+}
 
-    Ok(())
+/// Keeps the names starting with `prefix` and containing `substring`; both
+/// ignore case.
+fn filter_key_names(
+    keys: Vec<String>,
+    prefix: Option<&str>,
+    substring: Option<&str>,
+) -> Vec<String> {
+    let prefix = prefix.map(str::to_uppercase);
+    let substring = substring.map(str::to_uppercase);
+    keys.into_iter()
+        .filter(|key| {
+            prefix
+                .as_ref()
+                .is_none_or(|p| key.starts_with(p.as_str()))
+        })
+        .filter(|key| {
+            substring
+                .as_ref()
+                .is_none_or(|s| key.contains(s.as_str()))
+        })
+        .collect()
 }
 
-fn setup_logger() {
+/// Prints the matching key names, one per line, and returns how many there were.
+pub fn list_keys(prefix: Option<&str>, substring: Option<&str>) -> Result<usize> {
+    let keys = filter_key_names(collect_key_names(), prefix, substring);
+    for key in &keys {
+        println!("{key}");
+    }
+    Ok(keys.len())
+}
+
+fn setup_logger(format: LogFormat) {
     let mut builder = env_logger::Builder::new();
     builder.filter_level(log::LevelFilter::Info);
     let env = env_logger::Env::new()
         .filter("EVREMAP_LOG")
         .write_style("EVREMAP_LOG_STYLE");
     builder.parse_env(env);
-    builder.init();
+    builder.format(|buf, record| {
+        use std::io::Write;
+        let style = buf.default_level_style(record.level());
+        write!(
+            buf,
+            "[{} {style}{:<5}{style:#} {}",
+            buf.timestamp(),
+            record.level(),
+            record.target()
+        )?;
+        if let Some(device) = deviceinfo::current_device() {
+            write!(buf, " {device}")?;
+        }
+        writeln!(buf, "] {}", record.args())
+    });
+
+    let human = builder.build();
+    log::set_max_level(human.filter());
+    log::set_boxed_logger(pick_logger(format, journal::JOURNAL_SOCKET, human))
+        .expect("the logger is only set up once");
+}
+
+/// The journald logger when `format` asks for it and the journal listens on
+/// `journal_socket`; otherwise `human`.
+fn pick_logger(
+    format: LogFormat,
+    journal_socket: &str,
+    human: env_logger::Logger,
+) -> Box<dyn log::Log> {
+    if format == LogFormat::Journald {
+        match journal::connect(journal_socket) {
+            Ok(socket) => return Box::new(journal::JournaldLogger::new(socket, human)),
+            Err(err) => eprintln!("{err:#}; falling back to --log-format human"),
+        }
+    }
+    Box::new(human)
+}
+
+/// Returned by `get_device` when `--wait-timeout-secs` elapses before the
+/// device shows up.
+#[derive(thiserror::Error, Debug)]
+#[error("gave up waiting for the device after {0:?}")]
+struct WaitTimeout(Duration);
+
+/// How long `get_device` sleeps before looking for the device again, given
+/// how many times it has already looked and how long it has been waiting:
+/// one second more each time, up to 10s, but never past `timeout`. `None`
+/// once `timeout` has elapsed.
+fn wait_backoff(attempts: u32, elapsed: Duration, timeout: Option<Duration>) -> Option<Duration> {
+    const MAX_SLEEP: Duration = Duration::from_secs(10);
+    const ONE_SECOND: Duration = Duration::from_secs(1);
+
+    let sleep = ONE_SECOND
+        .saturating_mul(attempts)
+        .min(MAX_SLEEP);
+    match timeout {
+        None => Some(sleep),
+        Some(timeout) => {
+            let remaining = timeout.saturating_sub(elapsed);
+            (!remaining.is_zero()).then(|| sleep.min(remaining))
+        },
+    }
 }
 
 fn get_device(
-    device_name: &str,
+    matcher: &DeviceMatch,
     phys: Option<&str>,
     wait_for_device: bool,
+    wait_timeout: Option<Duration>,
+    probe: bool,
 ) -> anyhow::Result<DeviceInfo> {
-    match deviceinfo::DeviceInfo::with_name(device_name, phys) {
+    let mut err = match matcher.find(phys, probe) {
         Ok(dev) => return Ok(dev),
         Err(err) if !wait_for_device => return Err(err),
         Err(err) => {
             log::warn!("{err:#}. Will wait until it is attached.");
+            err
         },
-    }
-
-    const MAX_SLEEP: Duration = Duration::from_secs(10);
-    const ONE_SECOND: Duration = Duration::from_secs(1);
-    let mut sleep = ONE_SECOND;
+    };
 
+    let start = Instant::now();
+    let mut attempts = 1;
     loop {
+        let Some(sleep) = wait_backoff(attempts, start.elapsed(), wait_timeout) else {
+            let timeout = wait_timeout.unwrap_or_default();
+            return Err(err.context(WaitTimeout(timeout)));
+        };
         std::thread::sleep(sleep);
-        sleep = (sleep + ONE_SECOND).min(MAX_SLEEP);
+        attempts += 1;
 
-        match deviceinfo::DeviceInfo::with_name(device_name, phys) {
+        match matcher.find(phys, probe) {
             Ok(dev) => return Ok(dev),
-            Err(err) => {
-                log::debug!("{err:#}");
+            Err(next) => {
+                log::debug!("{next:#}");
+                err = next;
             },
         }
     }
 }
 
+fn validate(config_file: PathBuf) -> Result<()> {
+    let mapping_config = MappingConfig::from_file(&config_file)
+        .context(format!("loading MappingConfig from {}", config_file.display()))?;
+    let problems = mapping_config.validate();
+    for problem in &problems {
+        println!("{problem}");
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} found {} problem(s)", config_file.display(), problems.len());
+    }
+    println!("{}: OK, {} mappings", config_file.display(), mapping_config.mappings.len());
+    Ok(())
+}
+
+fn print_config(config_file: PathBuf) -> Result<()> {
+    let mapping_config = MappingConfig::from_file(&config_file)
+        .context(format!("loading MappingConfig from {}", config_file.display()))?;
+    print!("{}", render_mappings(&mapping_config.mappings));
+    Ok(())
+}
+
 fn debug_events(device: DeviceInfo) -> Result<()> {
     let f =
         std::fs::File::open(&device.path).context(format!("opening {}", device.path.display()))?;
@@ -135,64 +469,432 @@ fn debug_events(device: DeviceInfo) -> Result<()> {
                     log::info!("{key:?} {}", event.value);
                 }
             },
-            evdev_rs::ReadStatus::Sync => anyhow::bail!("ReadStatus::Sync!"),
+            evdev_rs::ReadStatus::Sync => {
+                log::warn!("the kernel dropped events; the state changes it missed follow");
+                for event in drain_sync_events(&input)? {
+                    if let EventCode::EV_KEY(key) = event.event_code {
+                        log::info!("{key:?} {}", event.value);
+                    }
+                }
+            },
         }
     }
 }
 
-fn do_remap(
-    config_file: PathBuf,
-    delay: f64,
-    device_name: Option<String>,
-    phys: Option<String>,
-    wait_for_device: bool,
-) -> Result<()> {
+/// Like `debug_events`, but writes every event, in the format described in
+/// `eventlog`, to `output`. The device isn't grabbed.
+fn record_events(device: DeviceInfo, output: PathBuf) -> Result<()> {
+    use std::io::Write;
+
+    let f =
+        std::fs::File::open(&device.path).context(format!("opening {}", device.path.display()))?;
+    let input = evdev_rs::Device::new_from_file(f).with_context(|| {
+        format!("failed to create new Device from file {}", device.path.display())
+    })?;
+    let file = std::fs::File::create(&output).context(format!("creating {}", output.display()))?;
+    let mut out = std::io::BufWriter::new(file);
+    writeln!(out, "# recorded from {} ({})", device.name, device.path.display())?;
+    log::info!("recording {} to {}; press Ctrl-C to stop", device.name, output.display());
+
+    loop {
+        let (status, event) =
+            input.next_event(evdev_rs::ReadFlag::NORMAL | evdev_rs::ReadFlag::BLOCKING)?;
+        match status {
+            evdev_rs::ReadStatus::Success => {
+                eventlog::write_event(&mut out, &event)?;
+                // Ctrl-C ends the recording, so don't hold back complete reports.
+                if matches!(event.event_code, EventCode::EV_SYN(_)) {
+                    out.flush()?;
+                }
+            },
+            evdev_rs::ReadStatus::Sync => {
+                writeln!(out, "# events dropped; resynced state follows")?;
+                for event in drain_sync_events(&input)? {
+                    eventlog::write_event(&mut out, &event)?;
+                }
+                out.flush()?;
+            },
+        }
+    }
+}
+
+/// Watches `device`, without grabbing it, for `duration` and prints a config
+/// skeleton for it to stdout.
+fn generate_config(device: DeviceInfo, duration: Duration) -> Result<()> {
+    let f =
+        std::fs::File::open(&device.path).context(format!("opening {}", device.path.display()))?;
+    let input = evdev_rs::Device::new_from_file(f).with_context(|| {
+        format!("failed to create new Device from file {}", device.path.display())
+    })?;
+
+    log::info!(
+        "press each key you may want to remap on {} within the next {}s",
+        device.name,
+        duration.as_secs()
+    );
+    let deadline = Instant::now() + duration;
+    let mut keys = scaffold::KeyCollector::default();
+    while let Some(left) = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())
+    {
+        if !wait_for_input(&input, Some(left))? {
+            continue;
+        }
+        let (status, event) =
+            input.next_event(evdev_rs::ReadFlag::NORMAL | evdev_rs::ReadFlag::BLOCKING)?;
+        match status {
+            evdev_rs::ReadStatus::Success => keys.write_event(&event)?,
+            evdev_rs::ReadStatus::Sync => {
+                for event in drain_sync_events(&input)? {
+                    keys.write_event(&event)?;
+                }
+            },
+        }
+    }
+    print!("{}", scaffold::config_skeleton(&device.name, &device.phys, keys.keys()));
+    Ok(())
+}
+
+/// Feeds a recording through the engine exactly as `run_mapper` would feed
+/// live input, but with stdout in place of the virtual device.
+fn replay(config_file: PathBuf, events: PathBuf) -> Result<()> {
+    let mapping_config = MappingConfig::from_file(&config_file)
+        .context(format!("loading MappingConfig from {}", config_file.display()))?;
+    let text = std::fs::read_to_string(&events).context(format!("reading {}", events.display()))?;
+    let events = eventlog::parse_events(&text).context(format!("parsing {}", events.display()))?;
+
+    let mut engine = RemapEngine::new(mapping_config.mappings);
+    engine.set_transparent_modifiers(mapping_config.transparent_modifiers);
+    engine.set_mode_switch_debounce(
+        mapping_config
+            .mode_switch_debounce_ms
+            .map(Duration::from_millis),
+    );
+    engine.set_debounce(
+        mapping_config
+            .debounce_ms
+            .map(Duration::from_millis),
+    );
+    engine.set_priority_over_length(mapping_config.priority_over_length);
+    if let Some(keys) = &mapping_config.typing_keys {
+        engine.set_typing_keys(keys.clone());
+    }
+    engine.set_unknown_value_policy(mapping_config.unknown_value_policy);
+    let mut out = eventlog::EventWriter(std::io::stdout().lock());
+    for event in &events {
+        match event.event_code {
+            EventCode::EV_KEY(key)
+                if !mapping_config
+                    .passthrough_codes
+                    .iter()
+                    .any(|p| p.matches(event)) =>
+            {
+                engine.update_with_event(&mut out, event, key)?
+            },
+            EventCode::EV_KEY(_) => out.write_event(event)?,
+            _ => engine.update_with_other_event(&mut out, event)?,
+        }
+    }
+    engine.flush_debounce(&mut out)
+}
+
+fn do_remap(args: RemapArgs) -> Result<()> {
+    let RemapArgs {
+        config_file,
+        config_dir,
+        delay,
+        device_name,
+        device_name_regex,
+        vendor_id,
+        product_id,
+        phys,
+        wait_for_device,
+        wait_timeout_secs,
+        watch_mode,
+        log_events,
+        probe,
+        no_grab,
+        dry_run,
+        status_path,
+    } = args;
+    let wait_timeout = (wait_timeout_secs > 0).then(|| Duration::from_secs(wait_timeout_secs));
+    // clap makes sure that exactly one of them is given.
+    let config_file = config_dir
+        .or(config_file)
+        .context("no config file given")?;
     let mut mapping_config = MappingConfig::from_file(&config_file)
         .context(format!("loading MappingConfig from {}", config_file.display()))?;
+    mapping_config.log_problems();
 
-    if let Some(device) = device_name {
-        mapping_config.device_name = Some(device);
+    if !device_name.is_empty() {
+        mapping_config.device_name = None;
+        mapping_config.device_name_regex = None;
+        mapping_config.devices = device_name;
+    }
+    if let Some(regex) = device_name_regex {
+        mapping_config.device_name = None;
+        mapping_config.devices.clear();
+        mapping_config.device_name_regex = Some(regex);
+    }
+    if let Some(vendor_id) = vendor_id {
+        mapping_config.vendor_id = Some(vendor_id);
+    }
+    if let Some(product_id) = product_id {
+        mapping_config.product_id = Some(product_id);
     }
     if let Some(phys) = phys {
         mapping_config.phys = Some(phys);
     }
+    if let Some(status_path) = status_path {
+        mapping_config.status_path = Some(status_path);
+    }
 
-    let device_name = mapping_config
-        .device_name
-        .as_deref()
-        .ok_or_else(|| {
-            anyhow::anyhow!(
-                "device_name is missing; specify it either in the config file or via the \
-                 --device-name command line option"
+    let named = |device: DeviceConfig| (DeviceMatch::Name(device.device_name.clone()), device);
+    // The top level device when it isn't matched by name, then the sections.
+    let matched_by = |matcher: DeviceMatch, device_name: String| {
+        let top_level = DeviceConfig {
+            device_name,
+            phys: mapping_config.phys.clone(),
+            mappings: mapping_config.mappings.clone(),
+            transparent_modifiers: mapping_config
+                .transparent_modifiers
+                .clone(),
+            mode_leds: mapping_config.mode_leds.clone(),
+        };
+        std::iter::once((matcher, top_level))
+            .chain(
+                mapping_config
+                    .device_sections
+                    .iter()
+                    .cloned()
+                    .map(named),
             )
-        })?;
+            .collect()
+    };
+    let targets: Vec<(DeviceMatch, DeviceConfig)> =
+        match (mapping_config.vendor_id, mapping_config.product_id) {
+            (Some(vendor), Some(product)) => matched_by(
+                DeviceMatch::Ids { vendor, product },
+                format!("{vendor:04x}:{product:04x}"),
+            ),
+            (None, None) => match mapping_config.device_name_regex.clone() {
+                Some(regex) => {
+                    let device_name = regex.to_string();
+                    matched_by(DeviceMatch::NameRegex(regex), device_name)
+                },
+                None => mapping_config
+                    .device_configs()
+                    .into_iter()
+                    .map(named)
+                    .collect(),
+            },
+            _ => anyhow::bail!("vendor_id and product_id have to be given together"),
+        };
+    if targets.is_empty() {
+        anyhow::bail!(
+            "device_name is missing; specify it either in the config file or via the \
+             --device-name command line option"
+        );
+    }
+    if targets.len() > 1 && mapping_config.state_file.is_some() {
+        anyhow::bail!("state_file can only be used with a single device");
+    }
+    if targets.len() > 1 && mapping_config.control_socket.is_some() {
+        anyhow::bail!("control_socket can only be used with a single device");
+    }
 
     log::warn!("Short delay: release any keys now!");
     std::thread::sleep(Duration::from_secs_f64(delay));
 
-    let device_info = get_device(device_name, mapping_config.phys.as_deref(), wait_for_device)?;
+    let devices = targets
+        .into_iter()
+        .map(|(matcher, device)| {
+            let device_info =
+                get_device(&matcher, device.phys.as_deref(), wait_for_device, wait_timeout, probe)?;
+            let section =
+                matches!(matcher, DeviceMatch::Name(_)).then(|| device.device_name.clone());
+            Ok((device_info, device, section))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let settings = RemapSettings { config_file, watch_mode, log_events, grab: !no_grab, dry_run };
+
+    if devices.len() == 1 {
+        let (device_info, device, section) = devices.into_iter().next().unwrap();
+        return run_device(device_info, device, section, &mapping_config, &settings, false);
+    }
+
+    // One thread per device. If one of them fails, the others shut down too.
+    let results = std::thread::scope(|scope| {
+        let threads: Vec<_> = devices
+            .into_iter()
+            .map(|(device_info, device, section)| {
+                let config = &mapping_config;
+                let settings = &settings;
+                std::thread::Builder::new()
+                    .name(device_info.name.clone())
+                    .spawn_scoped(scope, move || {
+                        deviceinfo::set_current_device(&device_info.name);
+                        let result =
+                            run_device(device_info, device, section, config, settings, true);
+                        if result.is_err() {
+                            signals::request_shutdown();
+                        }
+                        result
+                    })
+            })
+            .collect::<std::io::Result<_>>()?;
+        anyhow::Ok(
+            threads
+                .into_iter()
+                .map(|thread| {
+                    thread
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("device thread panicked")))
+                })
+                .collect::<Vec<_>>(),
+        )
+    })?;
+    results.into_iter().collect()
+}
+
+/// The command line options that apply to every device.
+struct RemapSettings {
+    config_file: PathBuf,
+    watch_mode: bool,
+    log_events: bool,
+    grab: bool,
+    dry_run: bool,
+}
 
-    let mut mapper = InputMapper::create_mapper(device_info.path, mapping_config.mappings)?;
+/// Grabs `device_info` and remaps it with the mappings of `device` until
+/// shutdown. `section` is the device name to look up again when the config is
+/// reloaded, `None` if it was matched by IDs. `threaded` is set when other
+/// devices are remapped by other threads of this process.
+fn run_device(
+    device_info: DeviceInfo,
+    device: DeviceConfig,
+    section: Option<String>,
+    mapping_config: &MappingConfig,
+    settings: &RemapSettings,
+    threaded: bool,
+) -> Result<()> {
+    /// How quickly a device thread notices a signal that another one received.
+    const WAKE_INTERVAL: Duration = Duration::from_millis(250);
+
+    let mut mapper = InputMapper::create_mapper(
+        device_info.path,
+        device.mappings,
+        &mapping_config.output_device,
+        settings.grab,
+        settings.dry_run,
+    )?;
+    mapper.set_heartbeat(
+        mapping_config
+            .heartbeat_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+    );
+    mapper.set_idle_exit(
+        mapping_config
+            .idle_exit_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+    );
+    mapper.set_mode_switch_debounce(
+        mapping_config
+            .mode_switch_debounce_ms
+            .map(Duration::from_millis),
+    );
+    mapper.set_debounce(
+        mapping_config
+            .debounce_ms
+            .map(Duration::from_millis),
+    );
+    mapper.set_unknown_value_policy(mapping_config.unknown_value_policy);
+    mapper.set_flight_recorder_size(mapping_config.flight_recorder_size);
+    mapper.set_priority_over_length(mapping_config.priority_over_length);
+    if let Some(keys) = &mapping_config.typing_keys {
+        mapper.set_typing_keys(keys.clone());
+    }
+    mapper.set_passthrough_codes(mapping_config.passthrough_codes.clone());
+    mapper.set_transparent_modifiers(device.transparent_modifiers);
+    mapper.set_mode_leds(device.mode_leds);
+    mapper.set_confirm_led(mapping_config.confirm_led);
+    mapper.set_watch_mode(settings.watch_mode);
+    mapper.set_log_events(settings.log_events);
+    mapper.set_mirror_leds(mapping_config.mirror_leds);
+    mapper.set_config_file(settings.config_file.clone(), section);
+    if threaded {
+        mapper.set_wake_interval(Some(WAKE_INTERVAL));
+    }
+    if let Some(path) = mapping_config.state_file.clone() {
+        mapper.set_state_file(path)?;
+    }
+    if let Some(path) = mapping_config.status_path.clone() {
+        mapper.set_status_path(path);
+    }
+    if mapping_config.notify_on_mode_change {
+        mapper.set_mode_notification(
+            mapping_config
+                .notify_command
+                .clone()
+                .unwrap_or_else(|| notify::DEFAULT_COMMAND.to_string()),
+        );
+    }
+    if let Some(path) = mapping_config.control_socket.as_deref() {
+        mapper.set_control_socket(path)?;
+    }
     mapper.run_mapper()
 }
 
+/// Exit status used when `idle_exit_secs` elapses, so that supervisors can tell
+/// a watchdog exit apart from a configuration error. This is `EX_TEMPFAIL`.
+const IDLE_EXIT_STATUS: i32 = 75;
+
+fn exit_on_idle(result: Result<()>) -> Result<()> {
+    if let Err(err) = &result
+        && let Some(idle) = err.downcast_ref::<IdleTimeout>()
+    {
+        log::error!("{idle}");
+        std::process::exit(IDLE_EXIT_STATUS);
+    }
+    result
+}
+
 fn main() -> Result<()> {
-    setup_logger();
     let cli = Cli::parse();
+    setup_logger(cli.log_format);
 
     match cli.cmd {
-        Some(Command::ListDevices) => deviceinfo::list_devices(),
-        Some(Command::ListKeys) => list_keys(),
+        Some(Command::ListDevices { json, filter }) => {
+            deviceinfo::list_devices(json, filter.as_deref())
+        },
+        Some(Command::ListKeys { prefix, filter }) => {
+            list_keys(prefix.as_deref(), filter.as_deref()).map(|_| ())
+        },
+        Some(Command::Validate { config_file }) => validate(config_file),
+        Some(Command::PrintConfig { config_file }) => print_config(config_file),
         Some(Command::DebugEvents { device_name, phys }) => {
-            let device_info = get_device(&device_name, phys.as_deref(), false)?;
+            let device_info =
+                get_device(&DeviceMatch::Name(device_name), phys.as_deref(), false, None, false)?;
             debug_events(device_info)
         },
-        Some(Command::Remap { config_file, delay, device_name, phys, wait_for_device }) => {
-            do_remap(config_file, delay, device_name, phys, wait_for_device)
+        Some(Command::RecordEvents { device_name, phys, output }) => {
+            let device_info =
+                get_device(&DeviceMatch::Name(device_name), phys.as_deref(), false, None, false)?;
+            record_events(device_info, output)
+        },
+        Some(Command::GenerateConfig { device_name, phys, duration_secs }) => {
+            let device_info =
+                get_device(&DeviceMatch::Name(device_name), phys.as_deref(), false, None, false)?;
+            generate_config(device_info, Duration::from_secs(duration_secs))
         },
+        Some(Command::Replay { config_file, events }) => replay(config_file, events),
+        Some(Command::Remap(args)) => exit_on_idle(do_remap(args)),
         None => {
             if let Some(config_file) = cli.config_file {
-                do_remap(config_file, 2.0, None, None, false)
+                exit_on_idle(do_remap(RemapArgs::with_defaults(config_file)))
             } else {
                 Cli::command().print_help()?;
                 println!();
@@ -206,11 +908,131 @@ fn main() -> Result<()> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn journald_logging_falls_back_to_human_without_a_journal() {
+        let info = || {
+            log::Record::builder()
+                .level(log::Level::Info)
+                .args(format_args!("switched to mode nav"))
+                .build()
+        };
+        let human = || {
+            env_logger::Builder::new()
+                .filter_level(log::LevelFilter::Info)
+                .build()
+        };
+        let dir = std::env::temp_dir().join(format!("evremap-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socket");
+        let path = path.to_str().unwrap();
+
+        let logger = pick_logger(LogFormat::Journald, path, human());
+        logger.log(&info());
+
+        let journal = std::os::unix::net::UnixDatagram::bind(path).unwrap();
+        journal.set_nonblocking(true).unwrap();
+        let logger = pick_logger(LogFormat::Journald, path, human());
+        logger.log(&info());
+        let mut buf = [0u8; 512];
+        let len = journal.recv(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"MESSAGE=switched to mode nav\n"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn key_names() {
+        let keys = collect_key_names();
+        assert!(keys.iter().any(|k| k == "KEY_A"));
+        assert!(keys.iter().any(|k| k == "KEY_LEFTCTRL"));
+        assert!(
+            !keys
+                .iter()
+                .any(|k| k == "KEY_MAX" || k == "KEY_CNT")
+        );
+        assert!(keys.is_sorted());
+    }
+
+    #[test]
+    fn key_names_by_prefix_or_substring() {
+        let function_keys = filter_key_names(collect_key_names(), Some("KEY_F"), None);
+        assert!(
+            function_keys
+                .iter()
+                .any(|k| k == "KEY_F1")
+        );
+        assert!(
+            function_keys
+                .iter()
+                .any(|k| k == "KEY_FORWARD")
+        );
+        assert!(
+            function_keys
+                .iter()
+                .all(|k| k.starts_with("KEY_F"))
+        );
+        assert_eq!(filter_key_names(collect_key_names(), Some("key_f1"), None)[0], "KEY_F1");
+
+        let media = filter_key_names(collect_key_names(), None, Some("media"));
+        assert!(media.iter().any(|k| k == "KEY_MEDIA"));
+        assert!(
+            media
+                .iter()
+                .all(|k| k.contains("MEDIA"))
+        );
+
+        let both = filter_key_names(collect_key_names(), Some("BTN_"), Some("left"));
+        assert_eq!(both, ["BTN_DPAD_LEFT", "BTN_LEFT"]);
+    }
+
+    #[test]
+    fn parse_validate_cmd() {
+        let cli = Cli::try_parse_from(["evremap", "validate", "foo.toml"]).expect("parse ok");
+        let Some(Command::Validate { config_file }) = cli.cmd else {
+            panic!("expected 'validate' subcommand");
+        };
+        assert_eq!(config_file, PathBuf::from("foo.toml"));
+    }
+
+    #[test]
+    fn parse_print_config_cmd() {
+        let cli = Cli::try_parse_from(["evremap", "print-config", "foo.toml"]).expect("parse ok");
+        let Some(Command::PrintConfig { config_file }) = cli.cmd else {
+            panic!("expected 'print-config' subcommand");
+        };
+        assert_eq!(config_file, PathBuf::from("foo.toml"));
+    }
+
+    #[test]
+    fn parse_replay_cmd() {
+        let cli =
+            Cli::try_parse_from(["evremap", "replay", "foo.toml", "keys.txt"]).expect("parse ok");
+        let Some(Command::Replay { config_file, events }) = cli.cmd else {
+            panic!("expected 'replay' subcommand");
+        };
+        assert_eq!(config_file, PathBuf::from("foo.toml"));
+        assert_eq!(events, PathBuf::from("keys.txt"));
+    }
+
     #[test]
     fn parse_default_cmd() {
         let cli = Cli::try_parse_from(["evremap", "foo.toml"]).expect("parse ok");
         assert!(cli.cmd.is_none());
         assert_eq!(cli.config_file, Some(PathBuf::from("foo.toml")));
+        assert_eq!(cli.log_format, LogFormat::Human);
+    }
+
+    #[test]
+    fn parse_log_format_after_subcommand() {
+        let cli = Cli::try_parse_from([
+            "evremap",
+            "remap",
+            "foo.toml",
+            "--log-format",
+            "journald",
+        ])
+        .expect("parse ok");
+        assert_eq!(cli.log_format, LogFormat::Journald);
     }
 
     #[test]
@@ -223,22 +1045,125 @@ mod tests {
             "1.5",
             "--device-name",
             "dev",
+            "--device-name",
+            "pad",
+            "--device-name-regex",
+            "^Keychron K2",
             "--phys",
             "p",
             "--wait-for-device",
+            "--wait-timeout-secs",
+            "30",
+            "--watch-mode",
+            "--log-events",
+            "--probe",
+            "--no-grab",
+            "--dry-run",
+            "--vendor-id",
+            "0x046d",
+            "--product-id",
+            "c52b",
+            "--status-path",
+            "/run/user/1000/evremap-mode",
         ])
         .expect("parse ok");
 
-        let Some(Command::Remap { config_file, delay, device_name, phys, wait_for_device }) =
-            cli.cmd
+        let Some(Command::Remap(RemapArgs {
+            config_file,
+            config_dir,
+            delay,
+            device_name,
+            device_name_regex,
+            vendor_id,
+            product_id,
+            phys,
+            wait_for_device,
+            wait_timeout_secs,
+            watch_mode,
+            log_events,
+            probe,
+            no_grab,
+            dry_run,
+            status_path,
+        })) = cli.cmd
         else {
             panic!("expected 'remap' subcommand");
         };
 
-        assert_eq!(config_file, PathBuf::from("foo.toml"));
+        assert_eq!(config_file, Some(PathBuf::from("foo.toml")));
+        assert_eq!(config_dir, None);
         assert!((delay - 1.5).abs() < f64::EPSILON);
-        assert_eq!(device_name.as_deref(), Some("dev"));
+        assert_eq!(device_name, ["dev", "pad"]);
+        assert_eq!(
+            device_name_regex
+                .map(|r| r.to_string())
+                .as_deref(),
+            Some("^Keychron K2")
+        );
         assert_eq!(phys.as_deref(), Some("p"));
         assert!(wait_for_device);
+        assert_eq!(wait_timeout_secs, 30);
+        assert!(watch_mode);
+        assert!(log_events);
+        assert!(probe);
+        assert!(no_grab);
+        assert!(dry_run);
+        assert_eq!(vendor_id, Some(0x046d));
+        assert_eq!(product_id, Some(0xc52b));
+        assert_eq!(status_path, Some(PathBuf::from("/run/user/1000/evremap-mode")));
+    }
+
+    #[test]
+    fn wait_backoff_schedule() {
+        let secs = Duration::from_secs;
+        let schedule: Vec<_> = (1..=12)
+            .map(|attempts| wait_backoff(attempts, Duration::ZERO, None).unwrap())
+            .collect();
+        assert_eq!(schedule[..3], [secs(1), secs(2), secs(3)]);
+        assert_eq!(schedule[9..], [secs(10), secs(10), secs(10)]);
+
+        // The last sleep is cut short to end right at the timeout.
+        assert_eq!(wait_backoff(5, secs(12), Some(secs(15))), Some(secs(3)));
+        assert_eq!(wait_backoff(2, secs(12), Some(secs(15))), Some(secs(2)));
+        assert_eq!(wait_backoff(5, secs(15), Some(secs(15))), None);
+        assert_eq!(wait_backoff(5, secs(16), Some(secs(15))), None);
+    }
+
+    #[test]
+    fn parses_config_dir_instead_of_a_file() {
+        let cli = Cli::try_parse_from([
+            "evremap",
+            "remap",
+            "--config-dir",
+            "/etc/evremap.d",
+        ])
+        .expect("parse ok");
+        let Some(Command::Remap(args)) = cli.cmd else {
+            panic!("expected 'remap' subcommand");
+        };
+        assert_eq!(args.config_dir, Some(PathBuf::from("/etc/evremap.d")));
+        assert_eq!(args.config_file, None);
+
+        assert!(
+            Cli::try_parse_from([
+                "evremap",
+                "remap",
+                "--config-dir",
+                "d",
+                "foo.toml"
+            ])
+            .is_err()
+        );
+        assert!(Cli::try_parse_from(["evremap", "remap", "--delay", "1"]).is_err());
+    }
+
+    #[test]
+    fn grabs_by_default() {
+        let cli = Cli::try_parse_from(["evremap", "remap", "foo.toml"]).expect("parse ok");
+        let Some(Command::Remap(args)) = cli.cmd else {
+            panic!("expected 'remap' subcommand");
+        };
+        assert!(!args.no_grab);
+        assert!(!RemapArgs::with_defaults(PathBuf::from("foo.toml")).no_grab);
     }
 }
@@ -1,8 +1,15 @@
+use crate::keysym::{self, Keysym};
 use anyhow::Context;
-pub use evdev_rs::enums::{EV_KEY as KeyCode, EventCode, EventType};
+use evdev_rs::InputEvent;
+use evdev_rs::enums::int_to_bus_type;
+pub use evdev_rs::enums::{EV_KEY as KeyCode, EV_LED, EventCode, EventType};
+use regex::Regex;
 use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
 fn all_ev_keys() -> Vec<KeyCode> {
@@ -12,51 +19,449 @@ fn all_ev_keys() -> Vec<KeyCode> {
         .collect()
 }
 
+/// Per mode, the physical modifiers that still reach the output.
+pub type TransparentModifiers = HashMap<String, HashSet<KeyCode>>;
+
+/// Per mode, the LED of the grabbed device that is lit while it is active.
+pub type ModeLeds = HashMap<String, EV_LED>;
+
+/// What to do with a key event whose value is none of release (0), press (1)
+/// or repeat (2), as some devices send.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownValuePolicy {
+    /// Write it to the output unchanged.
+    #[default]
+    Forward,
+    Drop,
+    /// Treat it as a release of the key.
+    ClampToRelease,
+}
+
+/// The resolved configuration.
+///
+/// A dual role's or layer's tap timeout is its own `tap_timeout_ms` if set,
+/// else the top level `tap_timeout_ms`, else 200ms; the result is stored in
+/// each `Mapping::DualRole`, `Mapping::ChordDualRole` and `Mapping::LayerHold`.
 #[derive(Debug, Clone)]
 pub struct MappingConfig {
     pub device_name: Option<String>,
+    /// Matches the device by name; takes precedence over `device_name`.
+    pub device_name_regex: Option<Regex>,
+    /// More devices to remap with the same mappings, each on its own thread.
+    pub devices: Vec<String>,
+    pub phys: Option<String>,
+    /// When both are set, the device is matched by these instead of `device_name`.
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub mappings: Vec<Mapping>,
+    /// Interval for the info-level heartbeat log; disabled when `None`.
+    pub heartbeat_secs: Option<u64>,
+    /// Exit (so a supervisor can restart us) after this long without input; disabled when `None`.
+    pub idle_exit_secs: Option<u64>,
+    pub passthrough_codes: Vec<PassthroughCode>,
+    pub mode_switch_debounce_ms: Option<u64>,
+    /// Drops a key release and the press of the same key that follows within this window.
+    pub debounce_ms: Option<u64>,
+    pub unknown_value_policy: UnknownValuePolicy,
+    /// Compare overlapping mappings by `priority` before input length.
+    pub priority_over_length: bool,
+    /// The keys caps word treats as part of a word; A-Z and the digits if unset.
+    pub typing_keys: Option<HashSet<KeyCode>>,
+    /// How many recent events the flight recorder keeps for a SIGUSR1 dump; 0 disables it.
+    pub flight_recorder_size: usize,
+    /// Where the active mode, caps word and armed one-shots are saved on SIGTERM/SIGINT
+    /// and restored from at startup.
+    pub state_file: Option<PathBuf>,
+    /// Where the active mode is published for status bars; a file or a named pipe.
+    pub status_path: Option<PathBuf>,
+    /// A Unix socket taking `mode get`, `mode set NAME` and `reload` commands.
+    pub control_socket: Option<PathBuf>,
+    /// Show a desktop notification on each mode change.
+    pub notify_on_mode_change: bool,
+    /// The notification command, with `{mode}` standing for the new mode.
+    pub notify_command: Option<String>,
+    /// Modes that only let the listed physical modifiers through.
+    pub transparent_modifiers: TransparentModifiers,
+    pub mode_leds: ModeLeds,
+    /// `[[device]]` entries; the fields above describe the implicit top level device.
+    pub device_sections: Vec<DeviceConfig>,
+    /// How the virtual device presents itself.
+    pub output_device: OutputDevice,
+    /// Copy the LED state the desktop sets on the virtual device to the grabbed one.
+    pub mirror_leds: bool,
+    /// Lit while a `[[confirm]]` chord is armed.
+    pub confirm_led: Option<EV_LED>,
+}
+
+/// The identity and auto-repeat of the virtual device. Unset fields keep what
+/// it copies from the grabbed device, except the name, which defaults to
+/// `evremap Virtual input for <path>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputDevice {
+    pub name: Option<String>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    /// A `BUS_*` number, such as 6 for `BUS_VIRTUAL`.
+    pub bustype: Option<u16>,
+    /// How long a key is held before the kernel starts repeating it.
+    pub repeat_delay_ms: Option<u32>,
+    /// The time between two repeats.
+    pub repeat_rate_ms: Option<u32>,
+}
+
+/// One device and the mappings that apply to it.
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub device_name: String,
     pub phys: Option<String>,
     pub mappings: Vec<Mapping>,
+    pub transparent_modifiers: TransparentModifiers,
+    pub mode_leds: ModeLeds,
 }
 
 impl MappingConfig {
+    /// `device_name` followed by `devices`, without repeats.
+    pub fn device_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for name in self
+            .device_name
+            .iter()
+            .chain(&self.devices)
+        {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+
+    /// Every device to remap by name: those named at the top level, which
+    /// share the top level mappings, then the `[[device]]` entries.
+    pub fn device_configs(&self) -> Vec<DeviceConfig> {
+        self.device_names()
+            .into_iter()
+            .map(|device_name| DeviceConfig {
+                device_name,
+                phys: self.phys.clone(),
+                mappings: self.mappings.clone(),
+                transparent_modifiers: self.transparent_modifiers.clone(),
+                mode_leds: self.mode_leds.clone(),
+            })
+            .chain(self.device_sections.iter().cloned())
+            .collect()
+    }
+
+    /// Semantic problems that parse fine but make a mapping useless.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut problems = validate_mappings(&self.mappings, &self.transparent_modifiers);
+        for device in &self.device_sections {
+            problems.extend(
+                validate_mappings(&device.mappings, &device.transparent_modifiers)
+                    .into_iter()
+                    .map(|problem| {
+                        ConfigError::InDevice(device.device_name.clone(), Box::new(problem))
+                    }),
+            );
+        }
+        problems
+    }
+
+    /// Logs what `validate` finds, for a config that is loaded to be used
+    /// rather than checked.
+    pub fn log_problems(&self) {
+        for problem in self.validate() {
+            log::warn!("{problem}");
+        }
+    }
+
+    /// Reads the config at `path`, or, if it's a directory, the fragments in it;
+    /// see `read_config_dir`.
     pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let path = path.as_ref();
-        let toml_data = std::fs::read_to_string(path)
-            .context(format!("reading toml from {}", path.display()))?;
-        let config_file: ConfigFile =
-            toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?;
+        let table = if path.is_dir() {
+            read_config_dir(path)?
+        } else {
+            read_config_table(path, &mut Vec::new())?
+        };
+        let config_file = ConfigFile::from_table(table)
+            .context(format!("parsing toml from {}", path.display()))?;
+        Self::from_config_file(config_file)
+            .context(format!("loading mappings from {}", path.display()))
+    }
+
+    fn from_config_file(config_file: ConfigFile) -> anyhow::Result<Self> {
+        let tap_timeout_ms = config_file
+            .tap_timeout_ms
+            .unwrap_or(DEFAULT_TAP_TIMEOUT_MS);
+        let (mappings, transparent_modifiers, mode_leds) = config_file
+            .sections
+            .into_mappings(tap_timeout_ms)?;
+        let device_sections = config_file
+            .device
+            .into_iter()
+            .map(|device| {
+                let (mappings, transparent_modifiers, mode_leds) = device
+                    .sections
+                    .into_mappings(tap_timeout_ms)?;
+                Ok(DeviceConfig {
+                    device_name: device.device_name,
+                    phys: device.phys,
+                    mappings,
+                    transparent_modifiers,
+                    mode_leds,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            device_name: config_file.device_name,
+            device_name_regex: config_file
+                .device_name_regex
+                .as_deref()
+                .map(parse_device_name_regex)
+                .transpose()?,
+            devices: config_file.devices,
+            phys: config_file.phys,
+            vendor_id: config_file.vendor_id,
+            product_id: config_file.product_id,
+            mappings,
+            heartbeat_secs: config_file.heartbeat_secs,
+            idle_exit_secs: config_file.idle_exit_secs,
+            passthrough_codes: config_file
+                .passthrough_codes
+                .into_iter()
+                .map(|p| p.code)
+                .collect(),
+            mode_switch_debounce_ms: config_file.mode_switch_debounce_ms,
+            debounce_ms: config_file.debounce_ms,
+            unknown_value_policy: config_file.unknown_value_policy,
+            priority_over_length: config_file.priority_over_length,
+            typing_keys: key_set(config_file.typing_keys),
+            flight_recorder_size: config_file.flight_recorder_size,
+            state_file: config_file.state_file,
+            status_path: config_file.status_path,
+            control_socket: config_file.control_socket,
+            notify_on_mode_change: config_file.notify_on_mode_change,
+            notify_command: config_file.notify_command,
+            transparent_modifiers,
+            mode_leds,
+            device_sections,
+            output_device: OutputDevice {
+                name: config_file.output_device_name,
+                vendor_id: config_file.output_vendor_id,
+                product_id: config_file.output_product_id,
+                bustype: config_file
+                    .output_bus
+                    .map(|bus| bus.bustype),
+                repeat_delay_ms: config_file.repeat_delay_ms,
+                repeat_rate_ms: config_file.repeat_rate_ms,
+            },
+            mirror_leds: config_file.mirror_leds,
+            confirm_led: config_file
+                .confirm_led
+                .map(|led| led.led),
+        })
+    }
+}
+
+/// True if `mode` is `default` or something in the config names it: the mode
+/// of a mapping, the scope or target of a mode switch, a layer, or a
+/// `transparent_modifiers` entry.
+pub fn defines_mode<'a>(
+    mappings: impl IntoIterator<Item = &'a Mapping>,
+    transparent_modifiers: &TransparentModifiers,
+    mode: &str,
+) -> bool {
+    mode == "default"
+        || transparent_modifiers.contains_key(mode)
+        || mappings.into_iter().any(|m| match m {
+            Mapping::DualRole { mode: m, .. }
+            | Mapping::ChordDualRole { mode: m, .. }
+            | Mapping::Remap { mode: m, .. }
+            | Mapping::MouseMove { mode: m, .. }
+            | Mapping::Scroll { mode: m, .. }
+            | Mapping::Confirm { mode: m, .. }
+            | Mapping::Macro { mode: m, .. }
+            | Mapping::TypeString { mode: m, .. } => m.as_deref() == Some(mode),
+            Mapping::ModeSwitch { mode: m, scope, .. } => {
+                m == mode || scope.as_deref() == Some(mode)
+            },
+            Mapping::ModeReset { scope, .. } => scope.as_deref() == Some(mode),
+            Mapping::LayerHold { mode: m, .. } => m == mode,
+            Mapping::ModePop { .. }
+            | Mapping::TapDance { .. }
+            | Mapping::OneShot { .. }
+            | Mapping::CapsWord { .. }
+            | Mapping::Leader { .. }
+            | Mapping::PauseFor { .. }
+            | Mapping::Bypass { .. }
+            | Mapping::RepeatLast { .. } => false,
+        })
+}
+
+fn validate_mappings(
+    mappings: &[Mapping],
+    transparent_modifiers: &TransparentModifiers,
+) -> Vec<ConfigError> {
+    let mut problems = Vec::new();
+    for (idx, mapping) in mappings.iter().enumerate() {
+        let input_is_empty = match mapping {
+            Mapping::DualRole { .. }
+            | Mapping::LayerHold { .. }
+            | Mapping::MouseMove { .. }
+            | Mapping::Scroll { .. }
+            | Mapping::TapDance { .. }
+            | Mapping::OneShot { .. }
+            | Mapping::Leader { .. } => false,
+            Mapping::Remap { input, .. }
+            | Mapping::ChordDualRole { input, .. }
+            | Mapping::ModeSwitch { input, .. }
+            | Mapping::Confirm { input, .. }
+            | Mapping::Macro { input, .. }
+            | Mapping::TypeString { input, .. }
+            | Mapping::ModePop { input }
+            | Mapping::ModeReset { input, .. }
+            | Mapping::CapsWord { input, .. }
+            | Mapping::PauseFor { input, .. }
+            | Mapping::Bypass { input }
+            | Mapping::RepeatLast { input } => input.is_empty(),
+        };
+        if input_is_empty {
+            problems.push(ConfigError::EmptyInput(idx));
+        }
+        // A switch or layer naming a mode doesn't define it for itself.
+        let others = mappings
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != idx);
+        if let Mapping::ModeSwitch { mode, .. } | Mapping::LayerHold { mode, .. } = mapping
+            && !defines_mode(others.map(|(_, m)| m), transparent_modifiers, mode)
+        {
+            problems.push(ConfigError::UnknownMode(idx, mode.clone()));
+        }
+    }
+    problems
+}
+
+impl MappingSections {
+    /// The mappings of one device, and its `transparent_modifiers` and `led`
+    /// per mode.
+    fn into_mappings(
+        self,
+        tap_timeout_ms: u64,
+    ) -> anyhow::Result<(Vec<Mapping>, TransparentModifiers, ModeLeds)> {
         let mut mappings = vec![];
-        for dual in config_file.dual_role {
-            mappings.push(dual.into());
+        for dual in self.dual_role {
+            mappings.push(dual.into_mapping(None, tap_timeout_ms));
         }
-        for remap in config_file.remap {
+        for chord in self.chord_dual_role {
+            mappings.push(chord.into_mapping(tap_timeout_ms));
+        }
+        for layer in self.layer {
+            mappings.push(layer.into_mapping(tap_timeout_ms));
+        }
+        for remap in self.remap {
             mappings.push(remap.into());
         }
+        for (input, output) in self.simple {
+            mappings.push(simple_remap(&input, &output)?);
+        }
+        for swap in self.swap {
+            mappings.extend(swap.into_mappings()?);
+        }
 
-        let has_global_default_switch = config_file
+        let has_global_default_switch = self
             .mode_switch
             .iter()
             .any(|ms| ms.mode == "default");
 
         let all_keys = all_ev_keys();
 
-        for (mode_name, section) in config_file.modes {
+        let mut transparent_modifiers = HashMap::new();
+        let mut mode_leds = HashMap::new();
+        for (mode_name, mut section) in self.modes {
+            if let Some(led) = section.led.take() {
+                mode_leds.insert(mode_name.clone(), led.led);
+            }
+            if let Some(mods) = section.transparent_modifiers.take() {
+                transparent_modifiers.insert(
+                    mode_name.clone(),
+                    mods.into_iter()
+                        .map(Into::into)
+                        .collect(),
+                );
+            }
             let allowed = section.allowed_inputs();
             let is_exclusive = section.exclusive;
-            mappings.extend(section.into_mappings(&mode_name));
+            mappings.extend(section.into_mappings(&mode_name, tap_timeout_ms));
             if is_exclusive {
                 mappings.extend(exclusive_noops(&mode_name, &allowed, &all_keys));
             }
         }
 
-        for ms in config_file.mode_switch {
+        for ms in self.mode_switch {
             mappings.push(ms.into());
         }
 
-        inject_emergency_default(&mut mappings, has_global_default_switch);
+        for confirm in self.confirm {
+            mappings.push(confirm.into());
+        }
+
+        for macro_config in self.macros {
+            mappings.push(macro_config.into());
+        }
+
+        for type_config in self.type_strings {
+            mappings.push(type_config.into());
+        }
+
+        for pop in self.pop {
+            mappings.push(pop.into());
+        }
+
+        for reset in self.reset_mode {
+            mappings.push(reset.into());
+        }
+
+        for dance in self.tap_dance {
+            mappings.push(dance.into_mapping(tap_timeout_ms));
+        }
+
+        for one_shot in self.one_shot {
+            mappings.push(one_shot.into());
+        }
+
+        for caps_word in self.caps_word {
+            mappings.push(caps_word.into());
+        }
+
+        for leader in self.leader {
+            mappings.push(leader.into());
+        }
+
+        for pause in self.pause {
+            mappings.push(pause.into());
+        }
+
+        for bypass in self.bypass {
+            mappings.push(bypass.into());
+        }
+
+        for repeat in self.repeat_last {
+            mappings.push(repeat.into());
+        }
+
+        for mouse_move in self.mouse_move {
+            mappings.push(mouse_move.into());
+        }
+
+        for scroll in self.scroll {
+            mappings.push(scroll.into());
+        }
 
-        Ok(Self { device_name: config_file.device_name, phys: config_file.phys, mappings })
+        inject_emergency_default(&mut mappings, has_global_default_switch);
+        Ok((mappings, transparent_modifiers, mode_leds))
     }
 }
 
@@ -68,6 +473,36 @@ impl MappingConfig {
 //     VisualLine,
 // }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MacroStep {
+    /// Press the chord, then release it.
+    Tap(Vec<KeyCode>),
+    /// Press `key` and release it once `duration` has passed; the steps after
+    /// it wait for the release.
+    Hold { key: KeyCode, duration: Duration },
+}
+
+impl MacroStep {
+    /// The keys the step presses.
+    pub fn keys(&self) -> &[KeyCode] {
+        match self {
+            MacroStep::Tap(chord) => chord,
+            MacroStep::Hold { key, .. } => std::slice::from_ref(key),
+        }
+    }
+}
+
+impl fmt::Display for MacroStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacroStep::Tap(chord) => write!(f, "{}", chord_seq(chord)),
+            MacroStep::Hold { key, duration } => {
+                write!(f, "hold {key:?} for {}ms", duration.as_millis())
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Mapping {
     DualRole {
@@ -76,18 +511,380 @@ pub enum Mapping {
         tap: Vec<KeyCode>,
         mode: Option<String>,
         // mode: Mode,
+        /// A release later than this after the press is a hold, and doesn't tap.
+        tap_timeout_ms: u64,
+        /// Drop autorepeats of `hold` until this long after the press.
+        hold_repeat_delay_ms: Option<u64>,
+    },
+    /// A `DualRole` whose input is a chord: holding all of `input` holds
+    /// `hold`, and releasing the chord within `tap_timeout_ms` of completing it,
+    /// with no other key pressed in between, taps `tap` instead.
+    ChordDualRole {
+        input: HashSet<KeyCode>,
+        hold: Vec<KeyCode>,
+        tap: Vec<KeyCode>,
+        tap_timeout_ms: u64,
+        mode: Option<String>,
     },
     Remap {
         input: HashSet<KeyCode>,
         output: HashSet<KeyCode>,
         mode: Option<String>,
         // mode: Mode,
+        /// When the chord ends because one of its keys was released, keep the
+        /// remaining held (non-modifier) keys suppressed until they are released too.
+        suppress_on_partial_release: bool,
+        /// Used instead of `output` when some key outside `input` is already
+        /// held at press time (`when_with_others`).
+        output_with_others: Option<HashSet<KeyCode>>,
+        /// Only match while these are held too. Unlike keys of `input`, they
+        /// still reach the output.
+        when_held: HashSet<KeyCode>,
+        /// Also keep `input` in the output, so that the keys emit themselves
+        /// alongside `output`.
+        passthrough: bool,
+        /// Modes in which this doesn't apply, on top of `mode`.
+        except_mode: Vec<String>,
+        /// Only match if all of `input` went down within this many milliseconds.
+        combo_term_ms: Option<u64>,
+        /// Whether holding `input` auto-repeats `output`; off for remaps that
+        /// should fire exactly once.
+        repeat: bool,
+        /// Decides between matching mappings with inputs of the same length;
+        /// the higher one wins.
+        priority: u8,
     },
     ModeSwitch {
         input: HashSet<KeyCode>,
         mode: String,
         scope: Option<String>,
+        /// Only active while `input` is held; releasing it restores the previous mode.
+        momentary: bool,
+        /// Layer `mode` over the active mode instead of replacing it.
+        push: bool,
+        /// Fall back to `default` after this long without a key event.
+        timeout_ms: Option<u64>,
+        /// As for `Remap`.
+        priority: u8,
+    },
+    /// Enters `mode` while `input` is held and restores the previous mode on
+    /// release, tapping `tap` if that came within `tap_timeout_ms`.
+    LayerHold { input: KeyCode, mode: String, tap: Vec<KeyCode>, tap_timeout_ms: u64 },
+    /// Leaves the innermost mode pushed by a `push` mode switch.
+    ModePop { input: HashSet<KeyCode> },
+    /// Goes back to `default` from whatever mode is active, dropping pushed and
+    /// momentary modes. Works in every mode unless `scope` names one.
+    ModeReset { input: HashSet<KeyCode>, scope: Option<String> },
+    /// Arm-then-confirm: the first activation only arms; a second activation within
+    /// `window_ms` taps `action`, otherwise the arming lapses.
+    Confirm { input: HashSet<KeyCode>, action: Vec<KeyCode>, window_ms: u64, mode: Option<String> },
+    /// Types `steps` one after the other on press, waiting a random number of
+    /// milliseconds out of `jitter_ms` before each key event but the first.
+    Macro {
+        input: HashSet<KeyCode>,
+        steps: Vec<MacroStep>,
+        jitter_ms: Range<u64>,
+        mode: Option<String>,
+    },
+    /// Types `text` on press, as if it had been entered on a US layout, with
+    /// `jitter_ms` like a `Macro`.
+    TypeString {
+        input: HashSet<KeyCode>,
+        text: String,
+        jitter_ms: Range<u64>,
+        mode: Option<String>,
+    },
+    /// Taps `taps[n - 1]` after `input` was tapped `n` times, each tap within
+    /// `term_ms` of the previous one. Extra taps count as the last entry.
+    TapDance { input: KeyCode, taps: Vec<Vec<KeyCode>>, term_ms: u64 },
+    /// Tapping `input` holds `modifier` for the next non-modifier key press only.
+    OneShot { input: KeyCode, modifier: KeyCode },
+    /// Shifts letters until a key in `terminators` is pressed. Without a list,
+    /// anything but the `typing_keys`, backspace and minus ends the word.
+    CapsWord { input: HashSet<KeyCode>, terminators: Option<HashSet<KeyCode>> },
+    /// After `input`, the following key taps are matched against `sequences`;
+    /// a complete sequence taps its chord. A tap that matches no sequence, or
+    /// `term_ms` without a tap, types the captured keys instead.
+    /// During the capture, `literal` makes the next key typed as itself and
+    /// ends the capture, like Vim's `Ctrl-V`.
+    Leader {
+        input: KeyCode,
+        sequences: Vec<(Vec<KeyCode>, Vec<KeyCode>)>,
+        term_ms: u64,
+        literal: Option<KeyCode>,
     },
+    /// Releases all synthetic output and forwards input verbatim for `secs` seconds.
+    PauseFor { input: HashSet<KeyCode>, secs: u64 },
+    /// Like `PauseFor`, but until `input` is pressed again.
+    Bypass { input: HashSet<KeyCode> },
+    /// Plays the `Macro` or `TypeString` that fired last once more, like Vim's `.`.
+    RepeatLast { input: HashSet<KeyCode> },
+    /// Moves the pointer by `dx`, `dy` on press and every `interval_ms` after,
+    /// for as long as `input` is held.
+    MouseMove { input: KeyCode, dx: i32, dy: i32, interval_ms: u64, mode: Option<String> },
+    /// Turns the wheel by `amount` on press and every `interval_ms` after, for
+    /// as long as `input` is held. Positive amounts scroll up, or right when
+    /// `horizontal` is set.
+    Scroll { input: KeyCode, amount: i32, horizontal: bool, interval_ms: u64, mode: Option<String> },
+}
+
+/// One line per mapping, numbered as in the debug logs. The engine picks
+/// between overlapping mappings by this order, so it is kept; only key sets
+/// are sorted.
+pub fn render_mappings(mappings: &[Mapping]) -> String {
+    mappings
+        .iter()
+        .enumerate()
+        .map(|(idx, map)| format!("#{idx} {map}\n"))
+        .collect()
+}
+
+/// `KEY_A+KEY_B`, sorted, or `nothing`.
+fn chord_set(keys: &HashSet<KeyCode>) -> String {
+    let mut names: Vec<String> = keys
+        .iter()
+        .map(|k| format!("{k:?}"))
+        .collect();
+    names.sort();
+    chord_names(names)
+}
+
+/// `KEY_A+KEY_B` in press order, or `nothing`.
+fn chord_seq(keys: &[KeyCode]) -> String {
+    chord_names(
+        keys.iter()
+            .map(|k| format!("{k:?}"))
+            .collect(),
+    )
+}
+
+fn chord_names(names: Vec<String>) -> String {
+    if names.is_empty() { "nothing".to_string() } else { names.join("+") }
+}
+
+/// Where a mapping applies: `in nav`, `in any mode`, or `in any mode but nav`.
+fn scope(mode: Option<&String>, except: &[String]) -> String {
+    match mode {
+        Some(mode) => format!("in {mode}"),
+        None if except.is_empty() => "in any mode".to_string(),
+        None => format!("in any mode but {}", except.join(", ")),
+    }
+}
+
+fn write_jitter(f: &mut fmt::Formatter<'_>, jitter_ms: &Range<u64>) -> fmt::Result {
+    if jitter_ms.end > 0 {
+        write!(f, ", jitter_ms={jitter_ms:?}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mapping::DualRole { input, hold, tap, mode, tap_timeout_ms, hold_repeat_delay_ms } => {
+                write!(
+                    f,
+                    "dual_role {input:?} hold {} tap {} {}, tap_timeout_ms={tap_timeout_ms}",
+                    chord_seq(hold),
+                    chord_seq(tap),
+                    scope(mode.as_ref(), &[])
+                )?;
+                if let Some(delay) = hold_repeat_delay_ms {
+                    write!(f, ", hold_repeat_delay_ms={delay}")?;
+                }
+                Ok(())
+            },
+            Mapping::ChordDualRole { input, hold, tap, tap_timeout_ms, mode } => write!(
+                f,
+                "chord_dual_role {} hold {} tap {} {}, tap_timeout_ms={tap_timeout_ms}",
+                chord_set(input),
+                chord_seq(hold),
+                chord_seq(tap),
+                scope(mode.as_ref(), &[])
+            ),
+            Mapping::Remap {
+                input,
+                output,
+                mode,
+                suppress_on_partial_release,
+                output_with_others,
+                when_held,
+                passthrough,
+                except_mode,
+                combo_term_ms,
+                repeat,
+                priority,
+            } => {
+                write!(
+                    f,
+                    "remap {} -> {} {}, priority={priority}",
+                    chord_set(input),
+                    chord_set(output),
+                    scope(mode.as_ref(), except_mode)
+                )?;
+                if let Some(others) = output_with_others {
+                    write!(f, ", output_with_others={}", chord_set(others))?;
+                }
+                if !when_held.is_empty() {
+                    write!(f, ", when_held={}", chord_set(when_held))?;
+                }
+                if let Some(term) = combo_term_ms {
+                    write!(f, ", combo_term_ms={term}")?;
+                }
+                if *passthrough {
+                    write!(f, ", passthrough")?;
+                }
+                if !suppress_on_partial_release {
+                    write!(f, ", keep_on_partial_release")?;
+                }
+                if !repeat {
+                    write!(f, ", no_repeat")?;
+                }
+                Ok(())
+            },
+            Mapping::ModeSwitch {
+                input,
+                mode,
+                scope: from,
+                momentary,
+                push,
+                timeout_ms,
+                priority,
+            } => {
+                write!(
+                    f,
+                    "mode_switch {} -> {mode} {}, priority={priority}",
+                    chord_set(input),
+                    scope(from.as_ref(), &[])
+                )?;
+                if *momentary {
+                    write!(f, ", momentary")?;
+                }
+                if *push {
+                    write!(f, ", push")?;
+                }
+                if let Some(timeout) = timeout_ms {
+                    write!(f, ", timeout_ms={timeout}")?;
+                }
+                Ok(())
+            },
+            Mapping::LayerHold { input, mode, tap, tap_timeout_ms } => write!(
+                f,
+                "layer {input:?} -> {mode} tap {}, tap_timeout_ms={tap_timeout_ms}",
+                chord_seq(tap)
+            ),
+            Mapping::ModePop { input } => write!(f, "pop {}", chord_set(input)),
+            Mapping::ModeReset { input, scope: from } => {
+                write!(f, "reset_mode {} {}", chord_set(input), scope(from.as_ref(), &[]))
+            },
+            Mapping::Confirm { input, action, window_ms, mode } => write!(
+                f,
+                "confirm {} -> {} {}, window_ms={window_ms}",
+                chord_set(input),
+                chord_seq(action),
+                scope(mode.as_ref(), &[])
+            ),
+            Mapping::Macro { input, steps, jitter_ms, mode } => {
+                let steps: Vec<String> = steps
+                    .iter()
+                    .map(MacroStep::to_string)
+                    .collect();
+                write!(
+                    f,
+                    "macro {} -> {} {}",
+                    chord_set(input),
+                    steps.join(", "),
+                    scope(mode.as_ref(), &[])
+                )?;
+                write_jitter(f, jitter_ms)
+            },
+            Mapping::TypeString { input, text, jitter_ms, mode } => {
+                write!(f, "type {} -> {text:?} {}", chord_set(input), scope(mode.as_ref(), &[]))?;
+                write_jitter(f, jitter_ms)
+            },
+            Mapping::TapDance { input, taps, term_ms } => {
+                let taps: Vec<String> = taps
+                    .iter()
+                    .map(|chord| chord_seq(chord))
+                    .collect();
+                write!(f, "tap_dance {input:?} -> {}, term_ms={term_ms}", taps.join(", "))
+            },
+            Mapping::OneShot { input, modifier } => write!(f, "one_shot {input:?} -> {modifier:?}"),
+            Mapping::CapsWord { input, terminators } => {
+                write!(f, "caps_word {}", chord_set(input))?;
+                if let Some(terminators) = terminators {
+                    let mut names: Vec<String> = terminators
+                        .iter()
+                        .map(|k| format!("{k:?}"))
+                        .collect();
+                    names.sort();
+                    write!(f, ", terminators={}", names.join(" "))?;
+                }
+                Ok(())
+            },
+            Mapping::Leader { input, sequences, term_ms, literal } => {
+                let sequences: Vec<String> = sequences
+                    .iter()
+                    .map(|(keys, chord)| {
+                        let keys: Vec<String> = keys
+                            .iter()
+                            .map(|k| format!("{k:?}"))
+                            .collect();
+                        format!("{} -> {}", keys.join(" "), chord_seq(chord))
+                    })
+                    .collect();
+                write!(f, "leader {input:?}: {}, term_ms={term_ms}", sequences.join("; "))?;
+                if let Some(literal) = literal {
+                    write!(f, ", literal={literal:?}")?;
+                }
+                Ok(())
+            },
+            Mapping::PauseFor { input, secs } => {
+                write!(f, "pause {}, secs={secs}", chord_set(input))
+            },
+            Mapping::Bypass { input } => write!(f, "bypass {}", chord_set(input)),
+            Mapping::RepeatLast { input } => write!(f, "repeat_last {}", chord_set(input)),
+            Mapping::MouseMove { input, dx, dy, interval_ms, mode } => write!(
+                f,
+                "mouse_move {input:?} dx={dx} dy={dy} {}, interval_ms={interval_ms}",
+                scope(mode.as_ref(), &[])
+            ),
+            Mapping::Scroll { input, amount, horizontal, interval_ms, mode } => write!(
+                f,
+                "scroll {input:?} {}={amount} {}, interval_ms={interval_ms}",
+                if *horizontal { "horizontal" } else { "vertical" },
+                scope(mode.as_ref(), &[])
+            ),
+        }
+    }
+}
+
+/// Expands one `[simple]` entry, `"KEY_A" = "KEY_B"`, into a single-key remap
+/// in the default mode.
+fn simple_remap(input: &str, output: &str) -> anyhow::Result<Mapping> {
+    let parse = |s: &str| KeyCodeWrapper::try_from(s.to_string()).map(KeyCode::from);
+    let entry = || format!("in [simple] entry `\"{input}\" = \"{output}\"`");
+    let input_key = parse(input).with_context(entry)?;
+    let output_key = parse(output).with_context(entry)?;
+    Ok(single_remap(input_key, output_key, "default".to_string()))
+}
+
+/// A plain remap of `input` to `output` in `mode`, as `[simple]` and `[[swap]]`
+/// produce them.
+fn single_remap(input: KeyCode, output: KeyCode, mode: String) -> Mapping {
+    Mapping::Remap {
+        input: [input].into_iter().collect(),
+        output: [output].into_iter().collect(),
+        mode: Some(mode),
+        suppress_on_partial_release: true,
+        output_with_others: None,
+        when_held: HashSet::new(),
+        passthrough: false,
+        except_mode: Vec::new(),
+        combo_term_ms: None,
+        repeat: true,
+        priority: DEFAULT_REMAP_PRIORITY,
+    }
 }
 
 fn exclusive_noops(mode: &str, allowed: &HashSet<KeyCode>, all_keys: &[KeyCode]) -> Vec<Mapping> {
@@ -100,6 +897,14 @@ fn exclusive_noops(mode: &str, allowed: &HashSet<KeyCode>, all_keys: &[KeyCode])
                 input,
                 output: HashSet::new(),
                 mode: Some(mode.to_string()),
+                suppress_on_partial_release: true,
+                output_with_others: None,
+                when_held: HashSet::new(),
+                passthrough: false,
+                except_mode: Vec::new(),
+                combo_term_ms: None,
+                repeat: true,
+                priority: DEFAULT_REMAP_PRIORITY,
             });
         }
     }
@@ -118,6 +923,10 @@ fn inject_emergency_default(mappings: &mut Vec<Mapping>, has_global_default_swit
             .collect(),
         mode: "default".to_string(),
         scope: None,
+        momentary: false,
+        push: false,
+        timeout_ms: None,
+        priority: DEFAULT_MODE_SWITCH_PRIORITY,
     });
 
     // let somemodes = Mapping::ModeSwitch {
@@ -161,12 +970,145 @@ impl From<KeyCodeWrapper> for KeyCode {
     }
 }
 
+/// An event type (`EV_REL`) or a single event code (`REL_DIAL`) that is forwarded
+/// to the virtual device verbatim, without going through the remapping engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PassthroughCode {
+    Type(EventType),
+    Code(EventCode),
+}
+
+impl PassthroughCode {
+    pub fn matches(&self, event: &InputEvent) -> bool {
+        match self {
+            Self::Type(ev_type) => event.event_type() == Some(*ev_type),
+            Self::Code(code) => event.event_code == *code,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct PassthroughCodeWrapper {
+    pub code: PassthroughCode,
+}
+
+impl std::convert::TryFrom<String> for PassthroughCodeWrapper {
+    type Error = ConfigError;
+
+    fn try_from(s: String) -> Result<PassthroughCodeWrapper, Self::Error> {
+        if let Some(ev_type) = EventType::from_str(&s) {
+            return Ok(PassthroughCodeWrapper { code: PassthroughCode::Type(ev_type) });
+        }
+        EventType::EV_SYN
+            .iter()
+            .find_map(|ev_type| EventCode::from_str(&ev_type, &s))
+            .map(|code| PassthroughCodeWrapper { code: PassthroughCode::Code(code) })
+            .ok_or(ConfigError::InvalidEventCode(s))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct BusTypeWrapper {
+    pub bustype: u16,
+}
+
+impl std::convert::TryFrom<String> for BusTypeWrapper {
+    type Error = ConfigError;
+
+    fn try_from(s: String) -> Result<BusTypeWrapper, Self::Error> {
+        (0..256)
+            .find(|&n| int_to_bus_type(n.into()).is_some_and(|bus| format!("{bus:?}") == s))
+            .map(|bustype| BusTypeWrapper { bustype })
+            .ok_or(ConfigError::InvalidBusType(s))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct LedWrapper {
+    pub led: EV_LED,
+}
+
+impl std::convert::TryFrom<String> for LedWrapper {
+    type Error = ConfigError;
+
+    fn try_from(s: String) -> Result<LedWrapper, Self::Error> {
+        match EventCode::from_str(&EventType::EV_LED, &s) {
+            Some(EventCode::EV_LED(led)) => Ok(LedWrapper { led }),
+            _ => Err(ConfigError::InvalidLed(s)),
+        }
+    }
+}
+
+/// A `jitter_ms` range such as `5..25`: at least 5 and less than 25 milliseconds.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct JitterWrapper {
+    pub range: Range<u64>,
+}
+
+impl std::convert::TryFrom<String> for JitterWrapper {
+    type Error = ConfigError;
+
+    fn try_from(s: String) -> Result<JitterWrapper, Self::Error> {
+        s.split_once("..")
+            .and_then(|(start, end)| Some(start.trim().parse().ok()?..end.trim().parse().ok()?))
+            .filter(|range: &Range<u64>| range.start <= range.end)
+            .map(|range| JitterWrapper { range })
+            .ok_or(ConfigError::InvalidJitter(s))
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("Invalid key `{0}`.  Use `evremap list-keys` to see possible keys.")]
     InvalidKey(String),
+    #[error("Invalid event type or code `{0}`.  Expected a name such as `EV_REL` or `REL_DIAL`.")]
+    InvalidEventCode(String),
+    #[error("Invalid bus `{0}`.  Expected a name such as `BUS_USB` or `BUS_VIRTUAL`.")]
+    InvalidBusType(String),
+    #[error("Invalid LED `{0}`.  Expected a name such as `LED_SCROLLL` or `LED_CAPSL`.")]
+    InvalidLed(String),
+    #[error(
+        "Keysym `{0}` has no evdev key of its own; it is typed with the chord `{1}`.  evremap \
+         remaps keys below the keyboard layout."
+    )]
+    ShiftedKeysym(String, &'static str),
+    #[error("Invalid jitter_ms `{0}`.  Expected a range of milliseconds such as `5..25`.")]
+    InvalidJitter(String),
     #[error("Impossible: parsed KEY_XXX but not into an EV_KEY")]
     ImpossibleParseKey,
+    #[error(
+        "mapping #{0}: switches to mode `{1}`, but no mapping belongs to that mode, so every key \
+         would pass through unchanged there"
+    )]
+    UnknownMode(usize, String),
+    #[error("mapping #{0}: `input` is empty, so it can never match")]
+    EmptyInput(usize),
+    #[error("[[swap]] of `{0:?}` with itself does nothing")]
+    SwapWithItself(KeyCode),
+    #[error("device `{0}`: {1}")]
+    InDevice(String, Box<ConfigError>),
+    #[error("Invalid device_name_regex `{0}`: {1}")]
+    InvalidDeviceNameRegex(String, regex::Error),
+    #[error("Alias `${0}` is not defined in [aliases]")]
+    UndefinedAlias(String),
+    #[error("Alias `${0}` refers to itself: {1}")]
+    RecursiveAlias(String, String),
+    #[error("Alias `${0}` stands for {1} keys, but only one fits here")]
+    AliasNotOneKey(String, usize),
+}
+
+/// Parses a key the way a config names it: `KEY_ESC`, or a keysym such as
+/// `Escape`.
+pub fn parse_key(name: &str) -> Result<KeyCode, ConfigError> {
+    KeyCodeWrapper::try_from(name.to_string()).map(|key| key.code)
+}
+
+pub fn parse_device_name_regex(pattern: &str) -> Result<Regex, ConfigError> {
+    Regex::new(pattern).map_err(|err| ConfigError::InvalidDeviceNameRegex(pattern.to_string(), err))
 }
 
 impl std::convert::TryFrom<String> for KeyCodeWrapper {
@@ -178,7 +1120,11 @@ impl std::convert::TryFrom<String> for KeyCodeWrapper {
                 EventCode::EV_KEY(code) => Ok(KeyCodeWrapper { code }),
                 _ => Err(ConfigError::ImpossibleParseKey),
             },
-            None => Err(ConfigError::InvalidKey(s)),
+            None => match keysym::lookup(&s) {
+                Some(Keysym::Key(code)) => Ok(KeyCodeWrapper { code }),
+                Some(Keysym::Shifted(chord)) => Err(ConfigError::ShiftedKeysym(s, chord)),
+                None => Err(ConfigError::InvalidKey(s)),
+            },
         }
     }
 }
@@ -188,179 +1134,2582 @@ struct DualRoleConfig {
     input: KeyCodeWrapper,
     hold: Vec<KeyCodeWrapper>,
     tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    tap_timeout_ms: Option<u64>,
+    #[serde(default)]
+    hold_repeat_delay_ms: Option<u64>,
 }
 
-impl From<DualRoleConfig> for Mapping {
-    fn from(val: DualRoleConfig) -> Self {
+/// Used when neither the dual role nor the top level config sets `tap_timeout_ms`.
+const DEFAULT_TAP_TIMEOUT_MS: u64 = 200;
+
+impl DualRoleConfig {
+    /// Shared by the top level `[[dual_role]]` list and the nested
+    /// `[modes.x.dual_role]` sections, so that both carry every field.
+    /// `default_tap_timeout_ms` applies when the entry doesn't set its own.
+    fn into_mapping(self, mode: Option<String>, default_tap_timeout_ms: u64) -> Mapping {
         Mapping::DualRole {
-            input: val.input.into(),
-            hold: val
+            input: self.input.into(),
+            hold: self
                 .hold
                 .into_iter()
                 .map(Into::into)
                 .collect(),
-            tap: val
+            tap: self
                 .tap
                 .into_iter()
                 .map(Into::into)
                 .collect(),
-            mode: None,
-            // mode: Mode::Insert,
+            mode,
+            tap_timeout_ms: self
+                .tap_timeout_ms
+                .unwrap_or(default_tap_timeout_ms),
+            hold_repeat_delay_ms: self.hold_repeat_delay_ms,
         }
     }
 }
 
 #[derive(Debug, Deserialize)]
-struct RemapConfig {
+struct SwapConfig {
+    keys: [KeyCodeWrapper; 2],
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+impl SwapConfig {
+    /// A remap each way. Output isn't matched against mappings again, so the
+    /// pair can't feed back into each other.
+    fn into_mappings(self) -> Result<[Mapping; 2], ConfigError> {
+        let [a, b] = self.keys.map(KeyCode::from);
+        if a == b {
+            return Err(ConfigError::SwapWithItself(a));
+        }
+        let mode = self
+            .mode
+            .unwrap_or_else(|| "default".to_string());
+        Ok([
+            single_remap(a, b, mode.clone()),
+            single_remap(b, a, mode),
+        ])
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChordDualRoleConfig {
     input: Vec<KeyCodeWrapper>,
-    output: Vec<KeyCodeWrapper>,
+    hold: Vec<KeyCodeWrapper>,
+    tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    tap_timeout_ms: Option<u64>,
     #[serde(default)]
     mode: Option<String>,
 }
 
-impl From<RemapConfig> for Mapping {
-    fn from(val: RemapConfig) -> Self {
-        Mapping::Remap {
-            input: val
+impl ChordDualRoleConfig {
+    fn into_mapping(self, default_tap_timeout_ms: u64) -> Mapping {
+        Mapping::ChordDualRole {
+            input: self
                 .input
                 .into_iter()
                 .map(Into::into)
                 .collect(),
-            output: val
-                .output
+            hold: self
+                .hold
                 .into_iter()
                 .map(Into::into)
                 .collect(),
-            // NOTE: If no mode is specified, treat it as the implicit "default" mode.
-            mode: Some(
-                val.mode
-                    .unwrap_or_else(|| "default".to_string()),
-            ),
-            // mode: Mode::Insert,
+            tap: self
+                .tap
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            tap_timeout_ms: self
+                .tap_timeout_ms
+                .unwrap_or(default_tap_timeout_ms),
+            mode: self.mode,
         }
     }
 }
 
 #[derive(Debug, Deserialize)]
-struct ModeSwitchConfig {
-    input: Vec<KeyCodeWrapper>,
+struct LayerConfig {
+    input: KeyCodeWrapper,
     mode: String,
+    #[serde(default)]
+    tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    tap_timeout_ms: Option<u64>,
 }
 
-impl From<ModeSwitchConfig> for Mapping {
-    fn from(val: ModeSwitchConfig) -> Self {
-        Mapping::ModeSwitch {
-            input: val
-                .input
+impl LayerConfig {
+    fn into_mapping(self, default_tap_timeout_ms: u64) -> Mapping {
+        Mapping::LayerHold {
+            input: self.input.into(),
+            mode: self.mode,
+            tap: self
+                .tap
                 .into_iter()
                 .map(Into::into)
                 .collect(),
-            mode: val.mode,
-            scope: None,
+            tap_timeout_ms: self
+                .tap_timeout_ms
+                .unwrap_or(default_tap_timeout_ms),
         }
     }
 }
 
+fn default_true() -> bool {
+    true
+}
+
 #[derive(Debug, Deserialize)]
-struct ModeSection {
+struct RemapConfig {
+    input: Vec<KeyCodeWrapper>,
+    #[serde(alias = "when_alone")]
+    output: Vec<KeyCodeWrapper>,
     #[serde(default)]
-    dual_role: Vec<DualRoleConfig>,
+    mode: Option<String>,
+    #[serde(default = "default_true")]
+    suppress_on_partial_release: bool,
     #[serde(default)]
-    remap: Vec<RemapConfig>,
-    #[serde(default, rename = "switch")]
-    switch_to: Vec<ModeSwitchConfig>,
+    when_with_others: Option<Vec<KeyCodeWrapper>>,
     #[serde(default)]
-    exclusive: bool,
+    when_held: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    passthrough: bool,
+    #[serde(default)]
+    except_mode: Vec<String>,
+    #[serde(default)]
+    combo_term_ms: Option<u64>,
+    #[serde(default = "default_true")]
+    repeat: bool,
+    #[serde(default)]
+    priority: Option<u8>,
 }
 
-impl ModeSection {
-    fn allowed_inputs(&self) -> HashSet<KeyCode> {
-        let mut allowed: HashSet<KeyCode> = HashSet::new();
-        for dual in &self.dual_role {
-            allowed.insert(dual.input.code);
-        }
-        for remap in &self.remap {
-            for i in &remap.input {
-                allowed.insert(i.code);
-            }
-        }
-        for ms in &self.switch_to {
-            for i in &ms.input {
-                allowed.insert(i.code);
-            }
+fn key_set(keys: Option<Vec<KeyCodeWrapper>>) -> Option<HashSet<KeyCode>> {
+    keys.map(|keys| {
+        keys.into_iter()
+            .map(Into::into)
+            .collect()
+    })
+}
+
+impl From<RemapConfig> for Mapping {
+    fn from(val: RemapConfig) -> Self {
+        Mapping::Remap {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            output: val
+                .output
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            // NOTE: If no mode is specified, treat it as the implicit "default" mode,
+            // unless `except_mode` is given: that applies in every other mode.
+            mode: match val.mode {
+                None if !val.except_mode.is_empty() => None,
+                mode => Some(mode.unwrap_or_else(|| "default".to_string())),
+            },
+            // mode: Mode::Insert,
+            suppress_on_partial_release: val.suppress_on_partial_release,
+            output_with_others: key_set(val.when_with_others),
+            when_held: val
+                .when_held
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            passthrough: val.passthrough,
+            except_mode: val.except_mode,
+            combo_term_ms: val.combo_term_ms,
+            repeat: val.repeat,
+            priority: val
+                .priority
+                .unwrap_or(DEFAULT_REMAP_PRIORITY),
         }
-        allowed
     }
+}
 
-    fn into_mappings(self, mode: &str) -> Vec<Mapping> {
-        let mut out = Vec::new();
+#[derive(Debug, Deserialize)]
+struct ModeSwitchConfig {
+    input: Vec<KeyCodeWrapper>,
+    mode: String,
+    #[serde(default)]
+    momentary: bool,
+    #[serde(default)]
+    push: bool,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    priority: Option<u8>,
+}
 
-        for dual in self.dual_role {
-            out.push(Mapping::DualRole {
-                input: dual.input.into(),
-                hold: dual
-                    .hold
-                    .into_iter()
-                    .map(Into::into)
-                    .collect(),
-                tap: dual
-                    .tap
-                    .into_iter()
-                    .map(Into::into)
-                    .collect(),
-                mode: Some(mode.to_string()),
-            });
+/// Without an explicit `priority`, a mode switch beats a remap with an input
+/// of the same length.
+pub const DEFAULT_REMAP_PRIORITY: u8 = 0;
+pub const DEFAULT_MODE_SWITCH_PRIORITY: u8 = 1;
+
+impl From<ModeSwitchConfig> for Mapping {
+    fn from(val: ModeSwitchConfig) -> Self {
+        Mapping::ModeSwitch {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            mode: val.mode,
+            scope: None,
+            momentary: val.momentary,
+            push: val.push,
+            timeout_ms: val.timeout_ms,
+            priority: val
+                .priority
+                .unwrap_or(DEFAULT_MODE_SWITCH_PRIORITY),
         }
+    }
+}
 
-        for remap in self.remap {
-            out.push(Mapping::Remap {
-                input: remap
-                    .input
-                    .into_iter()
-                    .map(Into::into)
-                    .collect(),
-                output: remap
-                    .output
-                    .into_iter()
-                    .map(Into::into)
-                    .collect(),
-                mode: Some(mode.to_string()),
-            });
+fn default_confirm_window_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmConfig {
+    input: Vec<KeyCodeWrapper>,
+    output: Vec<KeyCodeWrapper>,
+    #[serde(default = "default_confirm_window_ms")]
+    window_ms: u64,
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+impl From<ConfirmConfig> for Mapping {
+    fn from(val: ConfirmConfig) -> Self {
+        Mapping::Confirm {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            action: val
+                .output
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            window_ms: val.window_ms,
+            mode: val.mode,
         }
+    }
+}
 
-        for ms in self.switch_to {
-            out.push(Mapping::ModeSwitch {
-                input: ms
-                    .input
+#[derive(Debug, Deserialize)]
+struct MacroConfig {
+    input: Vec<KeyCodeWrapper>,
+    /// Each entry is a chord, e.g. `[["KEY_G"], ["KEY_LEFTCTRL", "KEY_C"]]`,
+    /// or a held key, e.g. `{ hold = "KEY_ENTER", duration_ms = 500 }`.
+    steps: Vec<MacroStepConfig>,
+    jitter_ms: Option<JitterWrapper>,
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MacroStepConfig {
+    Tap(Vec<KeyCodeWrapper>),
+    Hold { hold: KeyCodeWrapper, duration_ms: u64 },
+}
+
+impl From<MacroStepConfig> for MacroStep {
+    fn from(val: MacroStepConfig) -> Self {
+        match val {
+            MacroStepConfig::Tap(chord) => MacroStep::Tap(
+                chord
                     .into_iter()
                     .map(Into::into)
                     .collect(),
-                mode: ms.mode,
-                scope: Some(mode.to_string()),
-            });
+            ),
+            MacroStepConfig::Hold { hold, duration_ms } => {
+                MacroStep::Hold { key: hold.into(), duration: Duration::from_millis(duration_ms) }
+            },
         }
+    }
+}
 
-        out
+impl From<MacroConfig> for Mapping {
+    fn from(val: MacroConfig) -> Self {
+        Mapping::Macro {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            steps: val
+                .steps
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            jitter_ms: val.jitter_ms.map_or(0..0, |j| j.range),
+            mode: val.mode,
+        }
     }
 }
 
 #[derive(Debug, Deserialize)]
-struct ConfigFile {
+struct TypeConfig {
+    input: Vec<KeyCodeWrapper>,
+    text: String,
+    jitter_ms: Option<JitterWrapper>,
     #[serde(default)]
-    device_name: Option<String>,
+    mode: Option<String>,
+}
 
-    #[serde(default)]
-    phys: Option<String>,
+impl From<TypeConfig> for Mapping {
+    fn from(val: TypeConfig) -> Self {
+        Mapping::TypeString {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            text: val.text,
+            jitter_ms: val.jitter_ms.map_or(0..0, |j| j.range),
+            mode: val.mode,
+        }
+    }
+}
 
+#[derive(Debug, Deserialize)]
+struct ResetModeConfig {
+    input: Vec<KeyCodeWrapper>,
     #[serde(default)]
-    dual_role: Vec<DualRoleConfig>,
+    scope: Option<String>,
+}
+
+impl From<ResetModeConfig> for Mapping {
+    fn from(val: ResetModeConfig) -> Self {
+        Mapping::ModeReset {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            scope: val.scope,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PopConfig {
+    input: Vec<KeyCodeWrapper>,
+}
+
+impl From<PopConfig> for Mapping {
+    fn from(val: PopConfig) -> Self {
+        Mapping::ModePop {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
 
+#[derive(Debug, Deserialize)]
+struct TapDanceConfig {
+    input: KeyCodeWrapper,
+    /// The chord for one tap, then for two taps, and so on.
+    taps: Vec<Vec<KeyCodeWrapper>>,
     #[serde(default)]
-    remap: Vec<RemapConfig>,
+    term_ms: Option<u64>,
+}
+
+impl TapDanceConfig {
+    /// `default_term_ms` is the top level `tap_timeout_ms`.
+    fn into_mapping(self, default_term_ms: u64) -> Mapping {
+        Mapping::TapDance {
+            input: self.input.into(),
+            taps: self
+                .taps
+                .into_iter()
+                .map(|chord| {
+                    chord
+                        .into_iter()
+                        .map(Into::into)
+                        .collect()
+                })
+                .collect(),
+            term_ms: self.term_ms.unwrap_or(default_term_ms),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OneShotConfig {
+    input: KeyCodeWrapper,
+    modifier: KeyCodeWrapper,
+}
+
+impl From<OneShotConfig> for Mapping {
+    fn from(val: OneShotConfig) -> Self {
+        Mapping::OneShot { input: val.input.into(), modifier: val.modifier.into() }
+    }
+}
 
+#[derive(Debug, Deserialize)]
+struct CapsWordConfig {
+    input: Vec<KeyCodeWrapper>,
     #[serde(default)]
-    mode_switch: Vec<ModeSwitchConfig>,
+    terminators: Option<Vec<KeyCodeWrapper>>,
+}
+
+impl From<CapsWordConfig> for Mapping {
+    fn from(val: CapsWordConfig) -> Self {
+        Mapping::CapsWord {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            terminators: key_set(val.terminators),
+        }
+    }
+}
+
+fn default_leader_term_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaderSequenceConfig {
+    keys: Vec<KeyCodeWrapper>,
+    output: Vec<KeyCodeWrapper>,
+}
 
+#[derive(Debug, Deserialize)]
+struct LeaderConfig {
+    input: KeyCodeWrapper,
+    sequences: Vec<LeaderSequenceConfig>,
+    #[serde(default = "default_leader_term_ms")]
+    term_ms: u64,
     #[serde(default)]
-    modes: HashMap<String, ModeSection>,
+    literal: Option<KeyCodeWrapper>,
+}
+
+impl From<LeaderConfig> for Mapping {
+    fn from(val: LeaderConfig) -> Self {
+        let keys = |keys: Vec<KeyCodeWrapper>| -> Vec<KeyCode> {
+            keys.into_iter()
+                .map(Into::into)
+                .collect()
+        };
+        Mapping::Leader {
+            input: val.input.into(),
+            sequences: val
+                .sequences
+                .into_iter()
+                .map(|seq| (keys(seq.keys), keys(seq.output)))
+                .collect(),
+            term_ms: val.term_ms,
+            literal: val.literal.map(Into::into),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PauseConfig {
+    input: Vec<KeyCodeWrapper>,
+    secs: u64,
+}
+
+impl From<PauseConfig> for Mapping {
+    fn from(val: PauseConfig) -> Self {
+        Mapping::PauseFor {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            secs: val.secs,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BypassConfig {
+    input: Vec<KeyCodeWrapper>,
+}
+
+impl From<BypassConfig> for Mapping {
+    fn from(val: BypassConfig) -> Self {
+        Mapping::Bypass {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RepeatLastConfig {
+    input: Vec<KeyCodeWrapper>,
+}
+
+impl From<RepeatLastConfig> for Mapping {
+    fn from(val: RepeatLastConfig) -> Self {
+        Mapping::RepeatLast {
+            input: val
+                .input
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+/// About 60 steps a second, so that motion looks smooth.
+fn default_mouse_move_interval_ms() -> u64 {
+    16
+}
+
+#[derive(Debug, Deserialize)]
+struct MouseMoveConfig {
+    input: KeyCodeWrapper,
+    #[serde(default)]
+    dx: i32,
+    #[serde(default)]
+    dy: i32,
+    #[serde(default = "default_mouse_move_interval_ms")]
+    interval_ms: u64,
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+impl From<MouseMoveConfig> for Mapping {
+    fn from(val: MouseMoveConfig) -> Self {
+        Mapping::MouseMove {
+            input: val.input.into(),
+            dx: val.dx,
+            dy: val.dy,
+            interval_ms: val.interval_ms,
+            mode: val.mode,
+        }
+    }
+}
+
+/// A wheel notch every 50ms, about what a quick flick of a real wheel gives.
+fn default_scroll_interval_ms() -> u64 {
+    50
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrollConfig {
+    input: KeyCodeWrapper,
+    amount: i32,
+    #[serde(default)]
+    horizontal: bool,
+    #[serde(default = "default_scroll_interval_ms")]
+    interval_ms: u64,
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+impl From<ScrollConfig> for Mapping {
+    fn from(val: ScrollConfig) -> Self {
+        Mapping::Scroll {
+            input: val.input.into(),
+            amount: val.amount,
+            horizontal: val.horizontal,
+            interval_ms: val.interval_ms,
+            mode: val.mode,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ModeSection {
+    #[serde(default)]
+    dual_role: Vec<DualRoleConfig>,
+    #[serde(default)]
+    remap: Vec<RemapConfig>,
+    #[serde(default, rename = "switch")]
+    switch_to: Vec<ModeSwitchConfig>,
+    #[serde(default)]
+    exclusive: bool,
+    /// When set, held modifiers not in this list are dropped while the mode is active.
+    #[serde(default)]
+    transparent_modifiers: Option<Vec<KeyCodeWrapper>>,
+    /// Lit while the mode is active.
+    #[serde(default)]
+    led: Option<LedWrapper>,
+}
+
+impl ModeSection {
+    fn allowed_inputs(&self) -> HashSet<KeyCode> {
+        let mut allowed: HashSet<KeyCode> = HashSet::new();
+        for dual in &self.dual_role {
+            allowed.insert(dual.input.code);
+        }
+        for remap in &self.remap {
+            for i in &remap.input {
+                allowed.insert(i.code);
+            }
+        }
+        for ms in &self.switch_to {
+            for i in &ms.input {
+                allowed.insert(i.code);
+            }
+        }
+        allowed
+    }
+
+    fn into_mappings(self, mode: &str, tap_timeout_ms: u64) -> Vec<Mapping> {
+        let mut out = Vec::new();
+
+        for dual in self.dual_role {
+            out.push(dual.into_mapping(Some(mode.to_string()), tap_timeout_ms));
+        }
+
+        for remap in self.remap {
+            out.push(Mapping::Remap {
+                input: remap
+                    .input
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+                output: remap
+                    .output
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+                mode: Some(mode.to_string()),
+                suppress_on_partial_release: remap.suppress_on_partial_release,
+                output_with_others: key_set(remap.when_with_others),
+                when_held: remap
+                    .when_held
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+                passthrough: remap.passthrough,
+                except_mode: remap.except_mode,
+                combo_term_ms: remap.combo_term_ms,
+                repeat: remap.repeat,
+                priority: remap
+                    .priority
+                    .unwrap_or(DEFAULT_REMAP_PRIORITY),
+            });
+        }
+
+        for ms in self.switch_to {
+            out.push(Mapping::ModeSwitch {
+                input: ms
+                    .input
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+                mode: ms.mode,
+                scope: Some(mode.to_string()),
+                momentary: ms.momentary,
+                push: ms.push,
+                timeout_ms: ms.timeout_ms,
+                priority: ms
+                    .priority
+                    .unwrap_or(DEFAULT_MODE_SWITCH_PRIORITY),
+            });
+        }
+
+        out
+    }
+}
+
+/// Reads `path` together with the files it lists in `include`, which are
+/// relative to it. `stack` holds the files being read, to catch cycles.
+/// Files ending in `.json` are read as JSON, everything else as TOML.
+fn read_config_table(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<toml::Table> {
+    let canonical = path
+        .canonicalize()
+        .context(format!("reading toml from {}", path.display()))?;
+    if stack.contains(&canonical) {
+        let chain: Vec<String> = stack
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|file| file.display().to_string())
+            .collect();
+        anyhow::bail!("include cycle: {}", chain.join(" -> "));
+    }
+    let toml_data =
+        std::fs::read_to_string(path).context(format!("reading toml from {}", path.display()))?;
+    let is_json = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    let mut table: toml::Table = if is_json {
+        serde_json::from_str(&toml_data).context(format!("parsing json from {}", path.display()))?
+    } else {
+        toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?
+    };
+    let includes: Vec<PathBuf> = match table.remove("include") {
+        Some(includes) => includes
+            .try_into()
+            .context(format!("parsing `include` in {}", path.display()))?,
+        None => Vec::new(),
+    };
+
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for include in includes {
+        let included = read_config_table(&dir.join(include), stack)
+            .context(format!("included from {}", path.display()))?;
+        merge_tables(&mut table, included, false);
+    }
+    stack.pop();
+    Ok(table)
+}
+
+/// Reads every `*.toml` file in `dir`, in name order, and merges them like
+/// includes, except that a setting several give, such as `device_name`, takes
+/// the last file's value.
+fn read_config_dir(dir: &Path) -> anyhow::Result<toml::Table> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).context(format!("reading {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == "toml")
+        {
+            files.push(path);
+        }
+    }
+    if files.is_empty() {
+        anyhow::bail!("no *.toml files in {}", dir.display());
+    }
+    files.sort();
+    let mut table = toml::Table::new();
+    for file in files {
+        merge_tables(&mut table, read_config_table(&file, &mut Vec::new())?, true);
+    }
+    Ok(table)
+}
+
+/// Merges an included file into the one that includes it: lists such as
+/// `[[remap]]` are appended to and tables such as `[modes.nav]` merged. A
+/// setting both give keeps the including file's value, or with `later_wins`,
+/// the included one's.
+fn merge_tables(into: &mut toml::Table, from: toml::Table, later_wins: bool) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Array(ours)), toml::Value::Array(theirs)) => ours.extend(theirs),
+            (Some(toml::Value::Table(ours)), toml::Value::Table(theirs)) => {
+                merge_tables(ours, theirs, later_wins)
+            },
+            (Some(ours), theirs) if later_wins => *ours = theirs,
+            (Some(_), _) => {},
+            (None, value) => {
+                into.insert(key, value);
+            },
+        }
+    }
+}
+
+/// Fields whose keys may be given as `$alias`.
+const ALIAS_FIELDS: [&str; 4] = ["input", "output", "hold", "tap"];
+
+/// Appends the keys that alias `name` stands for to `out`. `stack` holds the
+/// aliases being expanded, outermost first, to catch cycles.
+fn expand_alias(
+    name: &str,
+    aliases: &BTreeMap<String, Vec<String>>,
+    stack: &mut Vec<String>,
+    out: &mut Vec<String>,
+) -> Result<(), ConfigError> {
+    if stack.iter().any(|seen| seen == name) {
+        let chain: Vec<String> = stack
+            .iter()
+            .chain(std::iter::once(&name.to_string()))
+            .map(|alias| format!("${alias}"))
+            .collect();
+        return Err(ConfigError::RecursiveAlias(name.to_string(), chain.join(" -> ")));
+    }
+    let keys = aliases
+        .get(name)
+        .ok_or_else(|| ConfigError::UndefinedAlias(name.to_string()))?;
+    stack.push(name.to_string());
+    for key in keys {
+        match key.strip_prefix('$') {
+            Some(inner) => expand_alias(inner, aliases, stack, out)?,
+            None => out.push(key.clone()),
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Replaces `$alias` references in the key fields anywhere below `value`. In
+/// a list an alias expands in place; a field that takes one key needs an
+/// alias for exactly one key.
+fn expand_aliases(
+    value: &mut toml::Value,
+    aliases: &BTreeMap<String, Vec<String>>,
+) -> Result<(), ConfigError> {
+    let expand = |name: &str| {
+        let mut keys = Vec::new();
+        expand_alias(name, aliases, &mut Vec::new(), &mut keys)?;
+        Ok::<_, ConfigError>(keys)
+    };
+    match value {
+        toml::Value::Table(table) => {
+            for (field, value) in table.iter_mut() {
+                if !ALIAS_FIELDS.contains(&field.as_str()) {
+                    expand_aliases(value, aliases)?;
+                    continue;
+                }
+                match value {
+                    toml::Value::String(key) => {
+                        if let Some(name) = key.strip_prefix('$') {
+                            match &expand(name)?[..] {
+                                [one] => *key = one.clone(),
+                                keys => {
+                                    return Err(ConfigError::AliasNotOneKey(
+                                        name.to_string(),
+                                        keys.len(),
+                                    ));
+                                },
+                            }
+                        }
+                    },
+                    toml::Value::Array(items) => {
+                        let mut expanded = Vec::with_capacity(items.len());
+                        for item in items.drain(..) {
+                            match item
+                                .as_str()
+                                .and_then(|key| key.strip_prefix('$'))
+                            {
+                                Some(name) => expanded.extend(
+                                    expand(name)?
+                                        .into_iter()
+                                        .map(toml::Value::String),
+                                ),
+                                None => expanded.push(item),
+                            }
+                        }
+                        *items = expanded;
+                    },
+                    _ => {},
+                }
+            }
+        },
+        toml::Value::Array(items) => {
+            for item in items {
+                expand_aliases(item, aliases)?;
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    device_name: Option<String>,
+
+    #[serde(default)]
+    device_name_regex: Option<String>,
+
+    #[serde(default)]
+    devices: Vec<String>,
+
+    #[serde(default)]
+    phys: Option<String>,
+
+    #[serde(default)]
+    vendor_id: Option<u16>,
+
+    #[serde(default)]
+    product_id: Option<u16>,
+
+    #[serde(flatten)]
+    sections: MappingSections,
+
+    /// Devices with mappings of their own.
+    #[serde(default)]
+    device: Vec<DeviceSection>,
+
+    #[serde(default)]
+    heartbeat_secs: Option<u64>,
+
+    #[serde(default)]
+    idle_exit_secs: Option<u64>,
+
+    #[serde(default)]
+    passthrough_codes: Vec<PassthroughCodeWrapper>,
+
+    #[serde(default)]
+    mode_switch_debounce_ms: Option<u64>,
+
+    #[serde(default)]
+    debounce_ms: Option<u64>,
+
+    #[serde(default)]
+    unknown_value_policy: UnknownValuePolicy,
+
+    #[serde(default)]
+    priority_over_length: bool,
+
+    /// For layouts where other keys type letters.
+    #[serde(default)]
+    typing_keys: Option<Vec<KeyCodeWrapper>>,
+
+    #[serde(default = "default_flight_recorder_size")]
+    flight_recorder_size: usize,
+
+    #[serde(default)]
+    state_file: Option<PathBuf>,
+
+    #[serde(default)]
+    status_path: Option<PathBuf>,
+
+    #[serde(default)]
+    control_socket: Option<PathBuf>,
+
+    #[serde(default)]
+    notify_on_mode_change: bool,
+
+    #[serde(default)]
+    notify_command: Option<String>,
+
+    /// Default for dual roles that don't set their own.
+    #[serde(default)]
+    tap_timeout_ms: Option<u64>,
+
+    #[serde(default)]
+    output_device_name: Option<String>,
+
+    #[serde(default)]
+    output_vendor_id: Option<u16>,
+
+    #[serde(default)]
+    output_product_id: Option<u16>,
+
+    #[serde(default)]
+    output_bus: Option<BusTypeWrapper>,
+
+    #[serde(default)]
+    repeat_delay_ms: Option<u32>,
+
+    #[serde(default)]
+    repeat_rate_ms: Option<u32>,
+
+    #[serde(default)]
+    mirror_leds: bool,
+
+    #[serde(default)]
+    confirm_led: Option<LedWrapper>,
+}
+
+impl ConfigFile {
+    #[cfg(test)]
+    fn parse(toml_src: &str) -> anyhow::Result<Self> {
+        Self::from_table(toml::from_str(toml_src)?)
+    }
+
+    /// Expands the `[aliases]` table, then reads the rest.
+    fn from_table(mut table: toml::Table) -> anyhow::Result<Self> {
+        let aliases: BTreeMap<String, Vec<String>> = match table.remove("aliases") {
+            Some(aliases) => aliases
+                .try_into()
+                .context("in [aliases]")?,
+            None => BTreeMap::new(),
+        };
+        let mut value = toml::Value::Table(table);
+        expand_aliases(&mut value, &aliases)?;
+        Ok(value.try_into()?)
+    }
+}
+
+/// A `[[device]]` entry.
+#[derive(Debug, Deserialize)]
+struct DeviceSection {
+    device_name: String,
+
+    #[serde(default)]
+    phys: Option<String>,
+
+    #[serde(flatten)]
+    sections: MappingSections,
+}
+
+/// The mapping tables, found both at the top level and in `[[device]]` entries.
+#[derive(Debug, Default, Deserialize)]
+struct MappingSections {
+    #[serde(default)]
+    dual_role: Vec<DualRoleConfig>,
+
+    #[serde(default)]
+    chord_dual_role: Vec<ChordDualRoleConfig>,
+
+    #[serde(default)]
+    remap: Vec<RemapConfig>,
+
+    /// One-to-one remaps written as `"KEY_A" = "KEY_B"`.
+    #[serde(default)]
+    simple: BTreeMap<String, String>,
+
+    #[serde(default)]
+    swap: Vec<SwapConfig>,
+
+    #[serde(default)]
+    mode_switch: Vec<ModeSwitchConfig>,
+
+    #[serde(default)]
+    confirm: Vec<ConfirmConfig>,
+
+    #[serde(default, rename = "macro")]
+    macros: Vec<MacroConfig>,
+
+    #[serde(default, rename = "type")]
+    type_strings: Vec<TypeConfig>,
+
+    #[serde(default)]
+    pause: Vec<PauseConfig>,
+
+    #[serde(default)]
+    bypass: Vec<BypassConfig>,
+
+    #[serde(default)]
+    repeat_last: Vec<RepeatLastConfig>,
+
+    #[serde(default)]
+    mouse_move: Vec<MouseMoveConfig>,
+
+    #[serde(default)]
+    scroll: Vec<ScrollConfig>,
+
+    #[serde(default)]
+    pop: Vec<PopConfig>,
+
+    #[serde(default)]
+    reset_mode: Vec<ResetModeConfig>,
+
+    #[serde(default)]
+    layer: Vec<LayerConfig>,
+
+    #[serde(default)]
+    tap_dance: Vec<TapDanceConfig>,
+
+    #[serde(default)]
+    one_shot: Vec<OneShotConfig>,
+
+    #[serde(default)]
+    caps_word: Vec<CapsWordConfig>,
+
+    #[serde(default)]
+    leader: Vec<LeaderConfig>,
+
+    #[serde(default)]
+    modes: HashMap<String, ModeSection>,
+}
+
+fn default_flight_recorder_size() -> usize {
+    64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::TimeVal;
+    use evdev_rs::enums::{EV_KEY, EV_REL};
+
+    fn load(toml_src: &str) -> anyhow::Result<MappingConfig> {
+        MappingConfig::from_config_file(ConfigFile::parse(toml_src)?)
+    }
+
+    #[test]
+    fn several_devices() {
+        let config = load(
+            r#"
+            device_name = "Keyboard left"
+            devices = ["Keyboard right", "Keyboard left", "Trackpad"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.device_names(), ["Keyboard left", "Keyboard right", "Trackpad"]);
+        assert!(
+            load("")
+                .unwrap()
+                .device_names()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn device_name_regex() {
+        let config = load(r#"device_name_regex = "^Keychron K2 .*$""#).unwrap();
+        assert!(
+            config
+                .device_name_regex
+                .unwrap()
+                .is_match("Keychron K2 (fw 1.05)")
+        );
+
+        let err = load(r#"device_name_regex = "Keychron (K2""#).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidDeviceNameRegex(pattern, _)) if pattern == "Keychron (K2"
+        ));
+    }
+
+    #[test]
+    fn renders_resolved_mappings() {
+        let config = load(
+            r#"
+            [simple]
+            KEY_CAPSLOCK = "KEY_ESC"
+
+            [[dual_role]]
+            input = "KEY_SPACE"
+            hold = ["KEY_LEFTCTRL"]
+            tap = ["KEY_SPACE"]
+
+            [[remap]]
+            input = ["KEY_LEFTALT", "KEY_J"]
+            output = ["KEY_DOWN"]
+            except_mode = ["nav"]
+
+            [[mode_switch]]
+            input = ["KEY_F1"]
+            mode = "nav"
+
+            [[modes.nav.remap]]
+            input = ["KEY_H"]
+            output = ["KEY_LEFT"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            render_mappings(&config.mappings),
+            "#0 dual_role KEY_SPACE hold KEY_LEFTCTRL tap KEY_SPACE in any mode, \
+             tap_timeout_ms=200\n#1 remap KEY_J+KEY_LEFTALT -> KEY_DOWN in any mode but nav, \
+             priority=0\n#2 remap KEY_CAPSLOCK -> KEY_ESC in default, priority=0\n#3 remap KEY_H \
+             -> KEY_LEFT in nav, priority=0\n#4 mode_switch KEY_F1 -> nav in any mode, \
+             priority=1\n#5 mode_switch KEY_BACKSLASH+KEY_LEFTCTRL -> default in any mode, \
+             priority=1\n"
+        );
+    }
+
+    #[test]
+    fn mode_leds() {
+        let config = load(
+            r#"
+            [modes.nav]
+            led = "LED_SCROLLL"
+
+            [[modes.nav.remap]]
+            input = ["KEY_H"]
+            output = ["KEY_LEFT"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mode_leds, [("nav".to_string(), EV_LED::LED_SCROLLL)].into());
+
+        let err = load(
+            r#"
+            [modes.nav]
+            led = "SCROLL"
+            "#,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("Invalid LED `SCROLL`"));
+    }
+
+    #[test]
+    fn mode_notifications() {
+        let config = load("").unwrap();
+        assert!(!config.notify_on_mode_change);
+        assert_eq!(config.notify_command, None);
+
+        let config = load(
+            r#"
+            notify_on_mode_change = true
+            notify_command = "dunstify -r 4242 {mode}"
+            "#,
+        )
+        .unwrap();
+        assert!(config.notify_on_mode_change);
+        assert_eq!(config.notify_command.as_deref(), Some("dunstify -r 4242 {mode}"));
+    }
+
+    #[test]
+    fn confirm_led() {
+        assert_eq!(load("").unwrap().confirm_led, None);
+        assert_eq!(
+            load(r#"confirm_led = "LED_COMPOSE""#)
+                .unwrap()
+                .confirm_led,
+            Some(EV_LED::LED_COMPOSE)
+        );
+    }
+
+    #[test]
+    fn mirror_leds() {
+        assert!(!load("").unwrap().mirror_leds);
+        assert!(
+            load("mirror_leds = true")
+                .unwrap()
+                .mirror_leds
+        );
+    }
+
+    #[test]
+    fn output_device_identity() {
+        assert_eq!(load("").unwrap().output_device, OutputDevice::default());
+
+        let config = load(
+            r#"
+            output_device_name = "evremap keyboard"
+            output_vendor_id = 0x1209
+            output_product_id = 0x0001
+            output_bus = "BUS_VIRTUAL"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.output_device, OutputDevice {
+            name: Some("evremap keyboard".to_string()),
+            vendor_id: Some(0x1209),
+            product_id: Some(0x0001),
+            bustype: Some(6),
+            ..OutputDevice::default()
+        });
+
+        let err = load(r#"output_bus = "USB""#).unwrap_err();
+        assert!(format!("{err:#}").contains("Invalid bus `USB`"));
+    }
+
+    #[test]
+    fn output_repeat() {
+        let config = load(
+            r#"
+            repeat_delay_ms = 300
+            repeat_rate_ms = 25
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            (config.output_device.repeat_delay_ms, config.output_device.repeat_rate_ms),
+            (Some(300), Some(25))
+        );
+    }
+
+    #[test]
+    fn device_sections_have_their_own_mappings() {
+        let config = load(
+            r#"
+            [[device]]
+            device_name = "Keyboard"
+
+            [[device.dual_role]]
+            input = "KEY_CAPSLOCK"
+            hold = ["KEY_LEFTCTRL"]
+            tap = ["KEY_ESC"]
+
+            [[device]]
+            device_name = "Macro pad"
+            phys = "usb-0000:00:14.0-2/input0"
+
+            [[device.remap]]
+            input = ["KEY_KP1"]
+            output = ["KEY_F13"]
+
+            [device.modes.nav]
+            "#,
+        )
+        .unwrap();
+        let devices = config.device_configs();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device_name, "Keyboard");
+        assert!(
+            devices[0]
+                .mappings
+                .iter()
+                .any(|m| matches!(m, Mapping::DualRole { input: EV_KEY::KEY_CAPSLOCK, .. }))
+        );
+        assert!(
+            !devices[0]
+                .mappings
+                .iter()
+                .any(|m| matches!(m, Mapping::Remap { .. }))
+        );
+        assert_eq!(devices[1].phys.as_deref(), Some("usb-0000:00:14.0-2/input0"));
+        assert!(
+            devices[1]
+                .mappings
+                .iter()
+                .any(|m| matches!(m, Mapping::Remap { .. }))
+        );
+        assert!(
+            !devices[1]
+                .mappings
+                .iter()
+                .any(|m| matches!(m, Mapping::DualRole { .. }))
+        );
+    }
+
+    #[test]
+    fn top_level_is_an_implicit_device() {
+        let config = load(
+            r#"
+            device_name = "Keyboard"
+
+            [[remap]]
+            input = ["KEY_A"]
+            output = ["KEY_B"]
+
+            [[device]]
+            device_name = "Macro pad"
+
+            [[device.mode_switch]]
+            input = ["KEY_KP0"]
+            mode = "missing"
+            "#,
+        )
+        .unwrap();
+        let devices = config.device_configs();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device_name, "Keyboard");
+        assert_eq!(devices[0].mappings, config.mappings);
+        let problems: Vec<String> = config
+            .validate()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(problems, [
+            "device `Macro pad`: mapping #0: switches to mode `missing`, but no mapping belongs \
+             to that mode, so every key would pass through unchanged there"
+        ]);
+    }
+
+    #[test]
+    fn when_alone_and_when_with_others() {
+        let config = load(
+            r#"
+            [[remap]]
+            input = ["KEY_J"]
+            when_alone = ["KEY_J"]
+            when_with_others = []
+            "#,
+        )
+        .unwrap();
+        let Mapping::Remap { output, output_with_others, .. } = &config.mappings[0] else {
+            panic!("expected a remap");
+        };
+        assert_eq!(*output, [EV_KEY::KEY_J].into_iter().collect());
+        assert_eq!(*output_with_others, Some(HashSet::new()));
+    }
+
+    #[test]
+    fn when_held() {
+        let config = load(
+            r#"
+            [[remap]]
+            input = ["KEY_J"]
+            output = ["KEY_DOWN"]
+            when_held = ["KEY_LEFTMETA"]
+
+            [[remap]]
+            input = ["KEY_K"]
+            output = ["KEY_UP"]
+            "#,
+        )
+        .unwrap();
+        let when_held = |idx: usize| match &config.mappings[idx] {
+            Mapping::Remap { when_held, .. } => when_held.clone(),
+            _ => panic!("expected a remap"),
+        };
+        assert_eq!(
+            when_held(0),
+            [EV_KEY::KEY_LEFTMETA]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(when_held(1), HashSet::new());
+    }
+
+    #[test]
+    fn except_mode() {
+        let config = load(
+            r#"
+            [[remap]]
+            input = ["KEY_CAPSLOCK"]
+            output = ["KEY_ESC"]
+            except_mode = ["gaming"]
+
+            [[remap]]
+            input = ["KEY_K"]
+            output = ["KEY_UP"]
+            mode = "nav"
+            except_mode = ["gaming"]
+
+            [[remap]]
+            input = ["KEY_J"]
+            output = ["KEY_DOWN"]
+            "#,
+        )
+        .unwrap();
+        let scope = |idx: usize| match &config.mappings[idx] {
+            Mapping::Remap { mode, except_mode, .. } => (mode.clone(), except_mode.clone()),
+            _ => panic!("expected a remap"),
+        };
+        // Without `mode`, except_mode applies everywhere else, not just in default.
+        assert_eq!(scope(0), (None, vec!["gaming".to_string()]));
+        assert_eq!(scope(1), (Some("nav".to_string()), vec!["gaming".to_string()]));
+        assert_eq!(scope(2), (Some("default".to_string()), vec![]));
+    }
+
+    #[test]
+    fn passthrough() {
+        let config = load(
+            r#"
+            [[remap]]
+            input = ["KEY_ENTER"]
+            output = ["KEY_F13"]
+            passthrough = true
+
+            [[remap]]
+            input = ["KEY_K"]
+            output = ["KEY_UP"]
+            "#,
+        )
+        .unwrap();
+        let passthrough = |idx: usize| match &config.mappings[idx] {
+            Mapping::Remap { passthrough, .. } => *passthrough,
+            _ => panic!("expected a remap"),
+        };
+        assert!(passthrough(0));
+        assert!(!passthrough(1));
+    }
+
+    #[test]
+    fn transparent_modifiers_per_mode() {
+        let config = load(
+            r#"
+            [modes.symbols]
+            transparent_modifiers = ["KEY_LEFTSHIFT", "KEY_RIGHTSHIFT"]
+
+            [modes.nav]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.transparent_modifiers["symbols"],
+            [EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_RIGHTSHIFT]
+                .into_iter()
+                .collect()
+        );
+        assert!(
+            !config
+                .transparent_modifiers
+                .contains_key("nav")
+        );
+    }
+
+    #[test]
+    fn macro_steps() {
+        let config = load(
+            r#"
+            [[macro]]
+            input = ["KEY_F5"]
+            steps = [["KEY_G"], ["KEY_G"], ["KEY_LEFTCTRL", "KEY_C"]]
+
+            [[macro]]
+            input = ["KEY_F6"]
+            steps = [{ hold = "KEY_ENTER", duration_ms = 500 }, ["KEY_A"]]
+            mode = "nav"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::Macro {
+            input: [EV_KEY::KEY_F5].into_iter().collect(),
+            steps: vec![
+                MacroStep::Tap(vec![EV_KEY::KEY_G]),
+                MacroStep::Tap(vec![EV_KEY::KEY_G]),
+                MacroStep::Tap(vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_C]),
+            ],
+            jitter_ms: 0..0,
+            mode: None,
+        });
+        assert_eq!(config.mappings[1], Mapping::Macro {
+            input: [EV_KEY::KEY_F6].into_iter().collect(),
+            steps: vec![
+                MacroStep::Hold { key: EV_KEY::KEY_ENTER, duration: Duration::from_millis(500) },
+                MacroStep::Tap(vec![EV_KEY::KEY_A]),
+            ],
+            jitter_ms: 0..0,
+            mode: Some("nav".to_string()),
+        });
+        assert_eq!(
+            config.mappings[1].to_string(),
+            "macro KEY_F6 -> hold KEY_ENTER for 500ms, KEY_A in nav"
+        );
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let config = load(
+            r#"
+            [[mode_switch]]
+            input = ["KEY_SPACE", "KEY_L"]
+            mode = "leader"
+            push = true
+
+            [[pop]]
+            input = ["KEY_ESC"]
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(&config.mappings[0], Mapping::ModeSwitch {
+            push: true,
+            momentary: false,
+            ..
+        }));
+        assert_eq!(config.mappings[1], Mapping::ModePop {
+            input: [EV_KEY::KEY_ESC].into_iter().collect()
+        });
+    }
+
+    #[test]
+    fn reset_mode() {
+        let config = load(
+            r#"
+            [[reset_mode]]
+            input = ["KEY_LEFTCTRL", "KEY_ESC"]
+
+            [[reset_mode]]
+            input = ["KEY_Q"]
+            scope = "leader"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::ModeReset {
+            input: [EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_ESC]
+                .into_iter()
+                .collect(),
+            scope: None,
+        });
+        assert_eq!(config.mappings[1], Mapping::ModeReset {
+            input: [EV_KEY::KEY_Q].into_iter().collect(),
+            scope: Some("leader".to_string()),
+        });
+    }
+
+    #[test]
+    fn layer() {
+        let config = load(
+            r#"
+            tap_timeout_ms = 150
+
+            [[layer]]
+            input = "KEY_SPACE"
+            mode = "nav"
+            tap = ["KEY_SPACE"]
+
+            [[layer]]
+            input = "KEY_TAB"
+            mode = "nav"
+            tap_timeout_ms = 300
+
+            [[remap]]
+            input = ["KEY_H"]
+            output = ["KEY_LEFT"]
+            mode = "nav"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::LayerHold {
+            input: EV_KEY::KEY_SPACE,
+            mode: "nav".to_string(),
+            tap: vec![EV_KEY::KEY_SPACE],
+            tap_timeout_ms: 150,
+        });
+        assert_eq!(config.mappings[1], Mapping::LayerHold {
+            input: EV_KEY::KEY_TAB,
+            mode: "nav".to_string(),
+            tap: vec![],
+            tap_timeout_ms: 300,
+        });
+        assert!(config.validate().is_empty());
+
+        let config = load(
+            r#"
+            [[layer]]
+            input = "KEY_SPACE"
+            mode = "nav"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.validate().len(), 1);
+    }
+
+    #[test]
+    fn tap_dance() {
+        let config = load(
+            r#"
+            tap_timeout_ms = 250
+
+            [[tap_dance]]
+            input = "KEY_SEMICOLON"
+            taps = [["KEY_SEMICOLON"], ["KEY_LEFTSHIFT", "KEY_SEMICOLON"]]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::TapDance {
+            input: EV_KEY::KEY_SEMICOLON,
+            taps: vec![vec![EV_KEY::KEY_SEMICOLON], vec![
+                EV_KEY::KEY_LEFTSHIFT,
+                EV_KEY::KEY_SEMICOLON
+            ]],
+            term_ms: 250,
+        });
+    }
+
+    #[test]
+    fn one_shot() {
+        let config = load(
+            r#"
+            [[one_shot]]
+            input = "KEY_LEFTSHIFT"
+            modifier = "KEY_LEFTSHIFT"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::OneShot {
+            input: EV_KEY::KEY_LEFTSHIFT,
+            modifier: EV_KEY::KEY_LEFTSHIFT,
+        });
+    }
+
+    #[test]
+    fn caps_word() {
+        let config = load(
+            r#"
+            [[caps_word]]
+            input = ["KEY_LEFTSHIFT", "KEY_RIGHTSHIFT"]
+
+            [[caps_word]]
+            input = ["KEY_F7"]
+            terminators = ["KEY_SPACE", "KEY_ENTER"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::CapsWord {
+            input: [EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_RIGHTSHIFT]
+                .into_iter()
+                .collect(),
+            terminators: None,
+        });
+        assert_eq!(config.mappings[1], Mapping::CapsWord {
+            input: [EV_KEY::KEY_F7].into_iter().collect(),
+            terminators: Some(
+                [EV_KEY::KEY_SPACE, EV_KEY::KEY_ENTER]
+                    .into_iter()
+                    .collect()
+            ),
+        });
+    }
+
+    #[test]
+    fn leader_sequences() {
+        let config = load(
+            r#"
+            [[leader]]
+            input = "KEY_RIGHTALT"
+            sequences = [
+                { keys = ["KEY_F", "KEY_F"], output = ["KEY_LEFTCTRL", "KEY_P"] },
+                { keys = ["KEY_W"], output = ["KEY_LEFTCTRL", "KEY_S"] },
+            ]
+            literal = "KEY_GRAVE"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::Leader {
+            input: EV_KEY::KEY_RIGHTALT,
+            sequences: vec![
+                (vec![EV_KEY::KEY_F, EV_KEY::KEY_F], vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_P]),
+                (vec![EV_KEY::KEY_W], vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_S]),
+            ],
+            term_ms: 1000,
+            literal: Some(EV_KEY::KEY_GRAVE),
+        });
+    }
+
+    /// A scratch directory holding `files`, removed again by the caller.
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("evremap-{name}-{}", std::process::id()));
+        for (file, contents) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn includes_are_merged() {
+        let dir = config_dir("include", &[
+            (
+                "config.toml",
+                r#"
+                device_name = "Keyboard"
+                include = ["modes/nav.toml", "modes/num.toml"]
+
+                [[remap]]
+                input = ["KEY_F1"]
+                output = ["KEY_MUTE"]
+                "#,
+            ),
+            (
+                "modes/nav.toml",
+                r#"
+                device_name = "ignored: the including file's setting wins"
+
+                [[mode_switch]]
+                input = ["KEY_F9"]
+                mode = "nav"
+
+                [[modes.nav.remap]]
+                input = ["KEY_H"]
+                output = ["KEY_LEFT"]
+                "#,
+            ),
+            (
+                "modes/num.toml",
+                r#"
+                [[remap]]
+                input = ["KEY_F2"]
+                output = ["KEY_VOLUMEDOWN"]
+
+                [[modes.nav.remap]]
+                input = ["KEY_L"]
+                output = ["KEY_RIGHT"]
+                "#,
+            ),
+        ]);
+        let config = MappingConfig::from_file(dir.join("config.toml")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.device_name.as_deref(), Some("Keyboard"));
+        let remaps = config
+            .mappings
+            .iter()
+            .filter(|m| matches!(m, Mapping::Remap { .. }))
+            .count();
+        assert_eq!(remaps, 4);
+        assert!(
+            config
+                .mappings
+                .iter()
+                .any(|m| matches!(m, Mapping::ModeSwitch { mode, .. } if mode == "nav"))
+        );
+    }
+
+    #[test]
+    fn config_dir_fragments_are_merged_in_name_order() {
+        let dir = config_dir("config-dir", &[
+            (
+                "10-base.toml",
+                r#"
+                device_name = "Keyboard"
+                phys = "usb-1"
+
+                [[remap]]
+                input = ["KEY_F1"]
+                output = ["KEY_MUTE"]
+                "#,
+            ),
+            (
+                "20-laptop.toml",
+                r#"
+                device_name = "AT Translated Set 2 keyboard"
+
+                [[remap]]
+                input = ["KEY_F2"]
+                output = ["KEY_VOLUMEDOWN"]
+                "#,
+            ),
+            ("README.md", "not a config"),
+        ]);
+        let config = MappingConfig::from_file(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.device_name.as_deref(), Some("AT Translated Set 2 keyboard"));
+        assert_eq!(config.phys.as_deref(), Some("usb-1"));
+        let inputs: Vec<_> = config
+            .mappings
+            .iter()
+            .filter_map(|m| match m {
+                Mapping::Remap { input, .. } => Some(input.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(inputs, [
+            [EV_KEY::KEY_F1].into_iter().collect(),
+            [EV_KEY::KEY_F2].into_iter().collect()
+        ]);
+    }
+
+    #[test]
+    fn empty_config_dir_is_an_error() {
+        let dir = config_dir("config-dir-empty", &[("notes.txt", "")]);
+        let err = MappingConfig::from_file(&dir).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(format!("{err:#}").contains("no *.toml files"), "{err:#}");
+    }
+
+    #[test]
+    fn json_and_toml_configs_are_equivalent() {
+        let dir = config_dir("json", &[
+            (
+                "config.toml",
+                r#"
+                device_name = "Keyboard"
+                tap_timeout_ms = 150
+
+                [[dual_role]]
+                input = "KEY_CAPSLOCK"
+                hold = ["KEY_LEFTCTRL"]
+                tap = ["KEY_ESC"]
+
+                [[remap]]
+                input = ["KEY_LEFTALT", "KEY_H"]
+                output = ["KEY_LEFT"]
+                repeat = false
+
+                [[modes.nav.remap]]
+                input = ["KEY_J"]
+                output = ["KEY_DOWN"]
+                "#,
+            ),
+            (
+                "config.json",
+                r#"{
+                    "device_name": "Keyboard",
+                    "tap_timeout_ms": 150,
+                    "dual_role": [
+                        {"input": "KEY_CAPSLOCK", "hold": ["KEY_LEFTCTRL"], "tap": ["KEY_ESC"]}
+                    ],
+                    "remap": [
+                        {"input": ["KEY_LEFTALT", "KEY_H"], "output": ["KEY_LEFT"], "repeat": false}
+                    ],
+                    "modes": {"nav": {"remap": [{"input": ["KEY_J"], "output": ["KEY_DOWN"]}]}}
+                }"#,
+            ),
+            ("broken.json", r#"{"device_name": "Keyboard",}"#),
+            ("null.json", r#"{"device_name": null}"#),
+        ]);
+        let from_toml = MappingConfig::from_file(dir.join("config.toml"));
+        let from_json = MappingConfig::from_file(dir.join("config.json"));
+        let broken = MappingConfig::from_file(dir.join("broken.json"));
+        let null = MappingConfig::from_file(dir.join("null.json"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (from_toml, from_json) = (from_toml.unwrap(), from_json.unwrap());
+        assert_eq!(from_json.device_name, from_toml.device_name);
+        assert_eq!(from_json.mappings, from_toml.mappings);
+        let message = format!("{:#}", broken.unwrap_err());
+        assert!(message.starts_with("parsing json from "), "{message}");
+        assert!(message.ends_with("broken.json: trailing comma at line 1 column 28"), "{message}");
+        // TOML has no null, so a field has to be left out instead.
+        let message = format!("{:#}", null.unwrap_err());
+        assert!(message.contains("invalid type: null"), "{message}");
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let dir = config_dir("include-cycle", &[
+            ("config.toml", r#"include = ["a.toml"]"#),
+            ("a.toml", r#"include = ["config.toml"]"#),
+        ]);
+        let err = MappingConfig::from_file(dir.join("config.toml")).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let message = format!("{err:#}");
+        assert!(message.starts_with("included from "), "{message}");
+        assert!(message.contains("a.toml: include cycle: "), "{message}");
+        assert!(message.ends_with("config.toml"), "{message}");
+    }
+
+    #[test]
+    fn aliases_expand_in_place() {
+        let config = load(
+            r#"
+            [aliases]
+            hyper = ["KEY_LEFTCTRL", "KEY_LEFTALT", "KEY_LEFTMETA"]
+            esc = ["KEY_ESC"]
+
+            [[remap]]
+            input = ["$hyper", "KEY_H"]
+            output = ["KEY_LEFT"]
+
+            [[dual_role]]
+            input = "KEY_CAPSLOCK"
+            hold = ["$hyper"]
+            tap = ["$esc"]
+            "#,
+        )
+        .unwrap();
+        let Mapping::Remap { input, .. } = &config.mappings[1] else {
+            panic!("expected a remap");
+        };
+        assert_eq!(
+            *input,
+            [
+                EV_KEY::KEY_LEFTCTRL,
+                EV_KEY::KEY_LEFTALT,
+                EV_KEY::KEY_LEFTMETA,
+                EV_KEY::KEY_H
+            ]
+            .into_iter()
+            .collect()
+        );
+        let Mapping::DualRole { hold, tap, .. } = &config.mappings[0] else {
+            panic!("expected a dual role");
+        };
+        assert_eq!(*hold, [
+            EV_KEY::KEY_LEFTCTRL,
+            EV_KEY::KEY_LEFTALT,
+            EV_KEY::KEY_LEFTMETA
+        ]);
+        assert_eq!(*tap, [EV_KEY::KEY_ESC]);
+    }
+
+    #[test]
+    fn nested_aliases() {
+        let aliases: BTreeMap<String, Vec<String>> = [
+            ("meh", vec![
+                "KEY_LEFTCTRL",
+                "KEY_LEFTALT",
+                "KEY_LEFTSHIFT",
+            ]),
+            ("hyper", vec!["$meh", "KEY_LEFTMETA"]),
+            ("launcher", vec!["$hyper", "KEY_SPACE"]),
+        ]
+        .into_iter()
+        .map(|(name, keys)| {
+            (
+                name.to_string(),
+                keys.into_iter()
+                    .map(String::from)
+                    .collect(),
+            )
+        })
+        .collect();
+        let mut keys = Vec::new();
+        expand_alias("launcher", &aliases, &mut Vec::new(), &mut keys).unwrap();
+        assert_eq!(keys, [
+            "KEY_LEFTCTRL",
+            "KEY_LEFTALT",
+            "KEY_LEFTSHIFT",
+            "KEY_LEFTMETA",
+            "KEY_SPACE"
+        ]);
+
+        // A single key alias fits a field that takes one key.
+        let config = load(
+            r#"
+            [aliases]
+            caps = ["$real_caps"]
+            real_caps = ["KEY_CAPSLOCK"]
+
+            [[dual_role]]
+            input = "$caps"
+            hold = ["KEY_LEFTCTRL"]
+            tap = ["KEY_ESC"]
+            "#,
+        )
+        .unwrap();
+        let Mapping::DualRole { input, .. } = &config.mappings[0] else {
+            panic!("expected a dual role");
+        };
+        assert_eq!(*input, EV_KEY::KEY_CAPSLOCK);
+    }
+
+    #[test]
+    fn alias_errors() {
+        let error = |toml_src: &str| load(toml_src).unwrap_err().to_string();
+        assert_eq!(
+            error(
+                r#"
+                [aliases]
+                a = ["$b"]
+                b = ["KEY_A", "$a"]
+
+                [[remap]]
+                input = ["$a"]
+                output = ["KEY_B"]
+                "#
+            ),
+            "Alias `$a` refers to itself: $a -> $b -> $a"
+        );
+        assert_eq!(
+            error(
+                r#"
+                [[remap]]
+                input = ["$nope"]
+                output = ["KEY_B"]
+                "#
+            ),
+            "Alias `$nope` is not defined in [aliases]"
+        );
+        assert_eq!(
+            error(
+                r#"
+                [aliases]
+                two = ["KEY_A", "KEY_B"]
+
+                [[dual_role]]
+                input = "$two"
+                hold = ["KEY_LEFTCTRL"]
+                tap = ["KEY_ESC"]
+                "#
+            ),
+            "Alias `$two` stands for 2 keys, but only one fits here"
+        );
+    }
+
+    #[test]
+    fn empty_output_blocks_the_key() {
+        let config = load(
+            r#"
+            [[remap]]
+            input = ["KEY_CAPSLOCK"]
+            output = []
+            "#,
+        )
+        .unwrap();
+        let Mapping::Remap { output, .. } = &config.mappings[0] else {
+            panic!("expected a remap");
+        };
+        assert!(output.is_empty());
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn remap_repeat_defaults_to_on() {
+        let config = load(
+            r#"
+            [[remap]]
+            input = ["KEY_F1"]
+            output = ["KEY_MUTE"]
+            repeat = false
+
+            [[modes.nav.remap]]
+            input = ["KEY_H"]
+            output = ["KEY_LEFT"]
+            "#,
+        )
+        .unwrap();
+        let repeats: Vec<bool> = config
+            .mappings
+            .iter()
+            .filter_map(|m| match m {
+                Mapping::Remap { repeat, .. } => Some(*repeat),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(repeats, [false, true]);
+    }
+
+    #[test]
+    fn mouse_button_remap() {
+        let config = load(
+            r#"
+            [[remap]]
+            input = ["KEY_CAPSLOCK"]
+            output = ["BTN_MIDDLE"]
+
+            [[remap]]
+            input = ["BTN_SIDE"]
+            output = ["KEY_BACK"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mappings[0],
+            single_remap(EV_KEY::KEY_CAPSLOCK, EV_KEY::BTN_MIDDLE, "default".to_string())
+        );
+        let Mapping::Remap { input, .. } = &config.mappings[1] else {
+            panic!("expected a remap");
+        };
+        assert_eq!(*input, [EV_KEY::BTN_SIDE].into_iter().collect());
+    }
+
+    #[test]
+    fn swap_is_two_remaps() {
+        let config = load(
+            r#"
+            [[swap]]
+            keys = ["KEY_CAPSLOCK", "KEY_ESC"]
+            "#,
+        )
+        .unwrap();
+        let remaps: Vec<_> = config
+            .mappings
+            .iter()
+            .filter(|m| matches!(m, Mapping::Remap { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(remaps, [
+            single_remap(EV_KEY::KEY_CAPSLOCK, EV_KEY::KEY_ESC, "default".to_string()),
+            single_remap(EV_KEY::KEY_ESC, EV_KEY::KEY_CAPSLOCK, "default".to_string()),
+        ]);
+
+        let err = load(
+            r#"
+            [[swap]]
+            keys = ["KEY_A", "KEY_A"]
+            "#,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("[[swap]] of `KEY_A` with itself"), "{err:#}");
+        assert!(load("[[swap]]\nkeys = [\"KEY_A\"]").is_err());
+    }
+
+    #[test]
+    fn chord_dual_role() {
+        let config = load(
+            r#"
+            tap_timeout_ms = 150
+            [[chord_dual_role]]
+            input = ["KEY_F", "KEY_J"]
+            hold = ["KEY_LEFTCTRL"]
+            tap = ["KEY_ESC"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::ChordDualRole {
+            input: [EV_KEY::KEY_F, EV_KEY::KEY_J]
+                .into_iter()
+                .collect(),
+            hold: vec![EV_KEY::KEY_LEFTCTRL],
+            tap: vec![EV_KEY::KEY_ESC],
+            tap_timeout_ms: 150,
+            mode: None,
+        });
+    }
+
+    #[test]
+    fn bypass() {
+        let config = load(
+            r#"
+            [[bypass]]
+            input = ["KEY_LEFTCTRL", "KEY_SCROLLLOCK"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::Bypass {
+            input: [EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_SCROLLLOCK]
+                .into_iter()
+                .collect(),
+        });
+    }
+
+    #[test]
+    fn repeat_last() {
+        let config = load(
+            r#"
+            [[repeat_last]]
+            input = ["KEY_RIGHTALT", "KEY_DOT"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::RepeatLast {
+            input: [EV_KEY::KEY_RIGHTALT, EV_KEY::KEY_DOT]
+                .into_iter()
+                .collect(),
+        });
+    }
+
+    #[test]
+    fn confirm() {
+        let config = load(
+            r#"
+            [[confirm]]
+            input = ["KEY_LEFTCTRL", "KEY_LEFTALT", "KEY_L"]
+            output = ["KEY_LEFTMETA", "KEY_L"]
+
+            [[confirm]]
+            input = ["KEY_Q"]
+            output = ["KEY_LEFTCTRL", "KEY_Q"]
+            window_ms = 800
+            mode = "nav"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::Confirm {
+            input: [
+                EV_KEY::KEY_LEFTCTRL,
+                EV_KEY::KEY_LEFTALT,
+                EV_KEY::KEY_L
+            ]
+            .into_iter()
+            .collect(),
+            action: vec![EV_KEY::KEY_LEFTMETA, EV_KEY::KEY_L],
+            window_ms: 1000,
+            mode: None,
+        });
+        assert_eq!(config.mappings[1], Mapping::Confirm {
+            input: [EV_KEY::KEY_Q].into_iter().collect(),
+            action: vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_Q],
+            window_ms: 800,
+            mode: Some("nav".to_string()),
+        });
+    }
+
+    #[test]
+    fn mouse_move() {
+        let config = load(
+            r#"
+            [[mouse_move]]
+            input = "KEY_H"
+            dx = -8
+            mode = "mouse"
+
+            [[mouse_move]]
+            input = "KEY_J"
+            dy = 8
+            interval_ms = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::MouseMove {
+            input: EV_KEY::KEY_H,
+            dx: -8,
+            dy: 0,
+            interval_ms: 16,
+            mode: Some("mouse".to_string()),
+        });
+        assert_eq!(config.mappings[1], Mapping::MouseMove {
+            input: EV_KEY::KEY_J,
+            dx: 0,
+            dy: 8,
+            interval_ms: 10,
+            mode: None,
+        });
+    }
+
+    #[test]
+    fn scroll() {
+        let config = load(
+            r#"
+            [[scroll]]
+            input = "KEY_K"
+            amount = 1
+            mode = "mouse"
+
+            [[scroll]]
+            input = "KEY_H"
+            amount = -2
+            horizontal = true
+            interval_ms = 80
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::Scroll {
+            input: EV_KEY::KEY_K,
+            amount: 1,
+            horizontal: false,
+            interval_ms: 50,
+            mode: Some("mouse".to_string()),
+        });
+        assert_eq!(config.mappings[1], Mapping::Scroll {
+            input: EV_KEY::KEY_H,
+            amount: -2,
+            horizontal: true,
+            interval_ms: 80,
+            mode: None,
+        });
+    }
+
+    #[test]
+    fn type_string() {
+        let config = load(
+            r#"
+            [[type]]
+            input = ["KEY_F6"]
+            text = "Grüße"
+
+            [[type]]
+            input = ["KEY_F7"]
+            text = "hi"
+            jitter_ms = "5..25"
+            mode = "nav"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mappings[0], Mapping::TypeString {
+            input: [EV_KEY::KEY_F6].into_iter().collect(),
+            text: "Grüße".to_string(),
+            jitter_ms: 0..0,
+            mode: None,
+        });
+        assert_eq!(config.mappings[1], Mapping::TypeString {
+            input: [EV_KEY::KEY_F7].into_iter().collect(),
+            text: "hi".to_string(),
+            jitter_ms: 5..25,
+            mode: Some("nav".to_string()),
+        });
+        assert_eq!(config.mappings[1].to_string(), "type KEY_F7 -> \"hi\" in nav, jitter_ms=5..25");
+
+        for bad in ["25..5", "5-25", "..25"] {
+            let err = load(&format!(
+                r#"
+                [[type]]
+                input = ["KEY_F7"]
+                text = "hi"
+                jitter_ms = "{bad}"
+                "#
+            ))
+            .unwrap_err();
+            assert!(format!("{err:#}").contains("Invalid jitter_ms"), "{bad}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn validate_reports_unknown_modes_and_empty_inputs() {
+        let config = load(
+            r#"
+            [[remap]]
+            input = []
+            output = ["KEY_A"]
+
+            [[mode_switch]]
+            input = ["KEY_F1"]
+            mode = "nav"
+
+            [[mode_switch]]
+            input = ["KEY_F2"]
+            mode = "typo"
+
+            [[modes.nav.remap]]
+            input = ["KEY_H"]
+            output = ["KEY_LEFT"]
+            "#,
+        )
+        .unwrap();
+        let problems: Vec<String> = config
+            .validate()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].starts_with("mapping #0: `input` is empty"));
+        assert!(problems[1].contains("`typo`"));
+    }
+
+    #[test]
+    fn dangling_mode_reference() {
+        let config = load(
+            r#"
+            [[mode_switch]]
+            input = ["KEY_F1"]
+            mode = "nav"
+
+            [[mode_switch]]
+            input = ["KEY_F2"]
+            mode = "gaming"
+
+            # A mode whose only mapping is the way back is still a mode.
+            [[modes.gaming.switch]]
+            input = ["KEY_F2"]
+            mode = "default"
+            "#,
+        )
+        .unwrap();
+        let problems: Vec<String> = config
+            .validate()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(problems, [
+            "mapping #1: switches to mode `nav`, but no mapping belongs to that mode, so every \
+             key would pass through unchanged there"
+        ]);
+    }
+
+    #[test]
+    fn tap_timeout_precedence() {
+        let dual_roles = r#"
+            [[dual_role]]
+            input = "KEY_CAPSLOCK"
+            hold = ["KEY_LEFTCTRL"]
+            tap = ["KEY_ESC"]
+            tap_timeout_ms = 150
+
+            [[dual_role]]
+            input = "KEY_ENTER"
+            hold = ["KEY_RIGHTCTRL"]
+            tap = ["KEY_ENTER"]
+            "#;
+        let timeouts = |config: MappingConfig| -> Vec<u64> {
+            config
+                .mappings
+                .iter()
+                .filter_map(|m| match m {
+                    Mapping::DualRole { tap_timeout_ms, .. } => Some(*tap_timeout_ms),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        assert_eq!(timeouts(load(dual_roles).unwrap()), [150, 200]);
+        let with_global = format!("tap_timeout_ms = 250\n{dual_roles}");
+        assert_eq!(timeouts(load(&with_global).unwrap()), [150, 250]);
+    }
+
+    #[test]
+    fn unknown_value_policy() {
+        assert_eq!(load("").unwrap().unknown_value_policy, UnknownValuePolicy::Forward);
+        let config = load(r#"unknown_value_policy = "clamp_to_release""#).unwrap();
+        assert_eq!(config.unknown_value_policy, UnknownValuePolicy::ClampToRelease);
+        assert!(load(r#"unknown_value_policy = "ignore""#).is_err());
+    }
+
+    #[test]
+    fn dual_role_hold_repeat_delay() {
+        let config = load(
+            r#"
+            [[dual_role]]
+            input = "KEY_SPACE"
+            hold = ["KEY_DOWN"]
+            tap = ["KEY_SPACE"]
+            hold_repeat_delay_ms = 400
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(config.mappings[0], Mapping::DualRole {
+            hold_repeat_delay_ms: Some(400),
+            ..
+        }));
+    }
+
+    #[test]
+    fn nested_mode_dual_role_keeps_tap_timeout() {
+        let config = load(
+            r#"
+            [[modes.nav.dual_role]]
+            input = "KEY_SPACE"
+            hold = ["KEY_LEFTSHIFT"]
+            tap = ["KEY_SPACE"]
+            tap_timeout_ms = 350
+
+            [[dual_role]]
+            input = "KEY_CAPSLOCK"
+            hold = ["KEY_LEFTCTRL"]
+            tap = ["KEY_ESC"]
+            "#,
+        )
+        .unwrap();
+        let nested = config
+            .mappings
+            .iter()
+            .find(|m| matches!(m, Mapping::DualRole { input: EV_KEY::KEY_SPACE, .. }))
+            .unwrap();
+        assert_eq!(*nested, Mapping::DualRole {
+            input: EV_KEY::KEY_SPACE,
+            hold: vec![EV_KEY::KEY_LEFTSHIFT],
+            tap: vec![EV_KEY::KEY_SPACE],
+            mode: Some("nav".to_string()),
+            tap_timeout_ms: 350,
+            hold_repeat_delay_ms: None,
+        });
+        assert!(
+            config
+                .mappings
+                .iter()
+                .any(|m| matches!(m, Mapping::DualRole {
+                    input: EV_KEY::KEY_CAPSLOCK,
+                    tap_timeout_ms: 200,
+                    ..
+                }))
+        );
+    }
+
+    #[test]
+    fn simple_table_expands_to_remaps() {
+        let config = load(
+            r#"
+            [simple]
+            KEY_CAPSLOCK = "KEY_ESC"
+            KEY_A = "KEY_B"
+            "#,
+        )
+        .unwrap();
+        let remap = |from, to| single_remap(from, to, "default".to_string());
+        assert_eq!(config.mappings[0], remap(EV_KEY::KEY_A, EV_KEY::KEY_B));
+        assert_eq!(config.mappings[1], remap(EV_KEY::KEY_CAPSLOCK, EV_KEY::KEY_ESC));
+    }
+
+    #[test]
+    fn simple_table_reports_offending_pair() {
+        let err = load(
+            r#"
+            [simple]
+            KEY_A = "KEY_NOPE"
+            "#,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains(r#""KEY_A" = "KEY_NOPE""#));
+    }
+
+    fn parse_passthrough(s: &str) -> Result<PassthroughCode, ConfigError> {
+        PassthroughCodeWrapper::try_from(s.to_string()).map(|p| p.code)
+    }
+
+    #[test]
+    fn passthrough_codes_parse_types_and_codes() {
+        assert_eq!(parse_passthrough("EV_REL").unwrap(), PassthroughCode::Type(EventType::EV_REL));
+        assert_eq!(
+            parse_passthrough("REL_DIAL").unwrap(),
+            PassthroughCode::Code(EventCode::EV_REL(EV_REL::REL_DIAL))
+        );
+        assert_eq!(
+            parse_passthrough("KEY_VOLUMEUP").unwrap(),
+            PassthroughCode::Code(EventCode::EV_KEY(EV_KEY::KEY_VOLUMEUP))
+        );
+        assert!(matches!(parse_passthrough("REL_NOPE"), Err(ConfigError::InvalidEventCode(_))));
+    }
+
+    #[test]
+    fn typing_keys_setting() {
+        assert_eq!(load("").unwrap().typing_keys, None);
+        let config = load(r#"typing_keys = ["KEY_SEMICOLON", "a"]"#).unwrap();
+        assert_eq!(config.typing_keys, Some(HashSet::from([EV_KEY::KEY_SEMICOLON, EV_KEY::KEY_A])));
+    }
+
+    #[test]
+    fn keys_fall_back_to_keysyms() {
+        let parse = |s: &str| KeyCodeWrapper::try_from(s.to_string()).map(|k| k.code);
+        assert_eq!(parse("KEY_ESC").unwrap(), EV_KEY::KEY_ESC);
+        assert_eq!(parse("Escape").unwrap(), EV_KEY::KEY_ESC);
+        assert_eq!(parse("Control_L").unwrap(), EV_KEY::KEY_LEFTCTRL);
+        assert!(matches!(parse("exclam"), Err(ConfigError::ShiftedKeysym(_, _))));
+        assert!(matches!(parse("KEY_NOPE"), Err(ConfigError::InvalidKey(_))));
+    }
+
+    #[test]
+    fn passthrough_code_matches_event() {
+        let time = TimeVal::new(0, 0);
+        let dial = InputEvent::new(&time, &EventCode::EV_REL(EV_REL::REL_DIAL), 1);
+        let wheel = InputEvent::new(&time, &EventCode::EV_REL(EV_REL::REL_WHEEL), 1);
+
+        assert!(PassthroughCode::Type(EventType::EV_REL).matches(&dial));
+        assert!(PassthroughCode::Code(dial.event_code).matches(&dial));
+        assert!(!PassthroughCode::Code(dial.event_code).matches(&wheel));
+        assert!(!PassthroughCode::Type(EventType::EV_KEY).matches(&wheel));
+    }
 }
@@ -0,0 +1,90 @@
+//! Shows a desktop notification when the mode changes, by running a command
+//! such as `notify-send`.
+//!
+//! The command is spawned and never waited for, so a notifier that hangs
+//! can't stall input; finished children are reaped on the next change.
+
+use std::process::{Child, Command, Stdio};
+
+pub const DEFAULT_COMMAND: &str = r#"notify-send "evremap" "mode: {mode}""#;
+
+pub struct ModeNotifier {
+    template: String,
+    children: Vec<Child>,
+}
+
+impl ModeNotifier {
+    pub fn new(template: String) -> Self {
+        Self { template, children: Vec::new() }
+    }
+
+    pub fn notify(&mut self, mode: &str) {
+        self.children
+            .retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+        let words = command_line(&self.template, mode);
+        let Some((program, args)) = words.split_first() else {
+            return;
+        };
+        match Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => self.children.push(child),
+            Err(err) => log::warn!("running `{program}` to announce mode {mode}: {err}"),
+        }
+    }
+}
+
+/// Splits `template` into words the way a shell would, honouring single and
+/// double quotes and backslash escapes, and replaces `{mode}` in each word.
+/// The mode never splits a word, whatever it contains.
+fn command_line(template: &str, mode: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"') | None, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    word.get_or_insert_default()
+                        .push(escaped);
+                }
+            },
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_default();
+            },
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    words
+        .into_iter()
+        .map(|word| word.replace("{mode}", mode))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_is_substituted_into_the_quoted_words() {
+        assert_eq!(command_line(DEFAULT_COMMAND, "nav"), ["notify-send", "evremap", "mode: nav"]);
+        assert_eq!(command_line("echo {mode}", "two words"), ["echo", "two words"]);
+        assert_eq!(command_line(r#"sh -c 'echo "$0"' {mode}-mode ''"#, "nav"), [
+            "sh",
+            "-c",
+            r#"echo "$0""#,
+            "nav-mode",
+            ""
+        ]);
+        assert_eq!(command_line(r"say a\ b", "nav"), ["say", "a b"]);
+        assert!(command_line("  ", "nav").is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+//! A "flight recorder": the last few events the engine processed, what it
+//! decided for them and what it emitted, kept in a fixed-size ring so that a
+//! trace can be dumped on demand (SIGUSR1) without running trace logging.
+
+use crate::mapping::KeyCode;
+use evdev_rs::TimeVal;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    /// An input key event, with the index of the mapping its press resolved to.
+    In { matched: Option<usize> },
+    /// Forwarded verbatim because remapping was paused or bypassed.
+    Paused,
+    /// A key event written to the output device.
+    Out,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+    pub time: TimeVal,
+    pub code: KeyCode,
+    pub value: i32,
+    pub kind: TraceKind,
+    /// Index into `FlightRecorder::mode_names`.
+    mode: Option<u16>,
+}
+
+pub struct FlightRecorder {
+    entries: Vec<TraceEntry>,
+    capacity: usize,
+    /// Where the next entry goes once the ring is full.
+    next: usize,
+    /// Mode names, interned so that entries stay `Copy`; grows only when a new mode is seen.
+    mode_names: Vec<String>,
+}
+
+impl FlightRecorder {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: Vec::with_capacity(capacity), capacity, next: 0, mode_names: Vec::new() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn intern_mode(&mut self, mode: Option<&str>) -> Option<u16> {
+        let mode = mode?;
+        if let Some(idx) = self
+            .mode_names
+            .iter()
+            .position(|m| m == mode)
+        {
+            return Some(idx as u16);
+        }
+        self.mode_names.push(mode.to_string());
+        Some((self.mode_names.len() - 1) as u16)
+    }
+
+    pub fn record(
+        &mut self,
+        time: TimeVal,
+        code: KeyCode,
+        value: i32,
+        kind: TraceKind,
+        mode: Option<&str>,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let entry = TraceEntry { time, code, value, kind, mode: self.intern_mode(mode) };
+        if self.entries.len() < self.capacity {
+            self.entries.push(entry);
+        } else {
+            self.entries[self.next] = entry;
+            self.next = (self.next + 1) % self.capacity;
+        }
+    }
+
+    /// Entries from oldest to newest.
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        let (newer, older) = self.entries.split_at(self.next);
+        older.iter().chain(newer)
+    }
+
+    pub fn format_entry(&self, entry: &TraceEntry) -> String {
+        let action = match entry.value {
+            0 => "release",
+            1 => "press",
+            2 => "repeat",
+            _ => "?",
+        };
+        let mode = entry
+            .mode
+            .map(|m| self.mode_names[m as usize].as_str())
+            .unwrap_or("<none>");
+        let decision = match entry.kind {
+            TraceKind::In { matched: Some(idx) } => format!("IN  -> mapping #{idx}"),
+            TraceKind::In { matched: None } => "IN ".to_string(),
+            TraceKind::Paused => "IN  (paused)".to_string(),
+            TraceKind::Out => "OUT".to_string(),
+        };
+        format!(
+            "[{}.{:06}] {decision} {:?} {action} (mode {mode})",
+            entry.time.tv_sec, entry.time.tv_usec, entry.code
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_keeps_the_newest_entries_in_order() {
+        let mut rec = FlightRecorder::new(3);
+        for sec in 0..5 {
+            rec.record(TimeVal::new(sec, 0), KeyCode::KEY_A, 1, TraceKind::Out, Some("default"));
+        }
+        let secs: Vec<_> = rec
+            .entries()
+            .map(|e| e.time.tv_sec)
+            .collect();
+        assert_eq!(secs, [2, 3, 4]);
+        assert_eq!(rec.entries.capacity(), 3);
+    }
+
+    #[test]
+    fn disabled_recorder_records_nothing() {
+        let mut rec = FlightRecorder::new(0);
+        rec.record(TimeVal::new(0, 0), KeyCode::KEY_A, 1, TraceKind::Out, None);
+        assert_eq!(rec.entries().count(), 0);
+    }
+
+    #[test]
+    fn formats_decision_and_mode() {
+        let mut rec = FlightRecorder::new(2);
+        rec.record(
+            TimeVal::new(12, 345),
+            KeyCode::KEY_J,
+            1,
+            TraceKind::In { matched: Some(3) },
+            Some("nav"),
+        );
+        let entry = *rec.entries().next().unwrap();
+        assert_eq!(
+            rec.format_entry(&entry),
+            "[12.000345] IN  -> mapping #3 KEY_J press (mode nav)"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! `generate-config`: a starting config for a device, with a commented-out
+//! remap for every key that was pressed while it watched.
+
+use crate::mapping::KeyCode;
+use crate::remapper::EventSink;
+use anyhow::Result;
+use evdev_rs::InputEvent;
+use evdev_rs::enums::EventCode;
+
+/// Takes the events read from the device and notes each key pressed, in the
+/// order they were first pressed.
+#[derive(Debug, Default)]
+pub struct KeyCollector {
+    keys: Vec<KeyCode>,
+}
+
+impl KeyCollector {
+    pub fn keys(&self) -> &[KeyCode] {
+        &self.keys
+    }
+}
+
+impl EventSink for KeyCollector {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        if let EventCode::EV_KEY(key) = event.event_code
+            && event.value == 1
+            && !self.keys.contains(&key)
+        {
+            self.keys.push(key);
+        }
+        Ok(())
+    }
+}
+
+/// The generated TOML: the device to match, then one `[[remap]]` stub per key.
+pub fn config_skeleton(device_name: &str, phys: &str, keys: &[KeyCode]) -> String {
+    let quote = |s: &str| toml::Value::String(s.to_string()).to_string();
+    let mut out = String::from(
+        "# Generated by `evremap generate-config`.  Uncomment the remaps you want\n# and fill in \
+         their output keys; `evremap list-keys` shows the names.\n",
+    );
+    out.push_str(&format!("device_name = {}\n", quote(device_name)));
+    if !phys.is_empty() {
+        out.push_str(&format!("# phys = {}\n", quote(phys)));
+    }
+    for key in keys {
+        out.push_str(&format!(
+            "\n# [[remap]]\n# input = [\"{}\"]\n# output = [\"{}\"]\n",
+            EventCode::EV_KEY(*key),
+            EventCode::EV_KEY(*key)
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::TimeVal;
+    use evdev_rs::enums::{EV_REL, EV_SYN};
+
+    #[test]
+    fn collects_each_pressed_key_once() {
+        let time = TimeVal::new(0, 0);
+        let mut collector = KeyCollector::default();
+        for (code, value) in [
+            (EventCode::EV_KEY(KeyCode::KEY_CAPSLOCK), 1),
+            (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+            (EventCode::EV_KEY(KeyCode::KEY_CAPSLOCK), 2),
+            (EventCode::EV_KEY(KeyCode::KEY_CAPSLOCK), 0),
+            (EventCode::EV_REL(EV_REL::REL_X), 3),
+            (EventCode::EV_KEY(KeyCode::KEY_J), 1),
+            (EventCode::EV_KEY(KeyCode::KEY_J), 0),
+            (EventCode::EV_KEY(KeyCode::KEY_CAPSLOCK), 1),
+        ] {
+            collector
+                .write_event(&InputEvent::new(&time, &code, value))
+                .unwrap();
+        }
+        assert_eq!(collector.keys(), [KeyCode::KEY_CAPSLOCK, KeyCode::KEY_J]);
+    }
+
+    #[test]
+    fn skeleton_parses_as_a_config() {
+        let text = config_skeleton("Kbd \"60%\"", "usb-1/input0", &[KeyCode::KEY_CAPSLOCK]);
+        assert!(text.contains("# [[remap]]\n# input = [\"KEY_CAPSLOCK\"]\n"));
+        let uncommented: String = text
+            .lines()
+            .skip(2)
+            .map(|line| line.strip_prefix("# ").unwrap_or(line))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let config: toml::Table = toml::from_str(&uncommented).unwrap();
+        assert_eq!(config["device_name"].as_str(), Some("Kbd \"60%\""));
+        assert_eq!(config["phys"].as_str(), Some("usb-1/input0"));
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{Result, bail};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Counted rather than flagged: with several devices, each event loop has to
+// act on the same signal, so none of them may clear it for the others.
+static DUMP_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static RELOAD_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_dump_signal(_signum: libc::c_int) {
+    DUMP_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+extern "C" fn on_reload_signal(_signum: libc::c_int) {
+    RELOAD_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+extern "C" fn on_shutdown_signal(_signum: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+fn install_handler(signum: libc::c_int, handler: extern "C" fn(libc::c_int)) -> Result<()> {
+    // SA_RESTART keeps the blocking read in the event loop from failing with EINTR;
+    // the loop notices the flag once the next event has been processed.
+    let rc = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signum, &action, std::ptr::null_mut())
+    };
+    if rc != 0 {
+        bail!("installing handler for signal {signum}: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Arranges for SIGUSR1 to request a diagnostics dump from the event loop.
+pub fn install_dump_handler() -> Result<()> {
+    install_handler(libc::SIGUSR1, on_dump_signal)
+}
+
+/// The SIGUSR1 and SIGHUP requests one event loop has already handled.
+#[derive(Debug, Default)]
+pub struct Requests {
+    dump: u64,
+    reload: u64,
+}
+
+impl Requests {
+    /// Returns true (once) if SIGUSR1 was received since the last call.
+    pub fn take_dump(&mut self) -> bool {
+        take(&DUMP_REQUESTS, &mut self.dump)
+    }
+
+    /// Returns true (once) if SIGHUP was received since the last call.
+    pub fn take_reload(&mut self) -> bool {
+        take(&RELOAD_REQUESTS, &mut self.reload)
+    }
+}
+
+fn take(requests: &AtomicU64, seen: &mut u64) -> bool {
+    let count = requests.load(Ordering::Relaxed);
+    std::mem::replace(seen, count) != count
+}
+
+/// Arranges for SIGTERM and SIGINT to request a clean shutdown instead of
+/// killing the process. `poll` is never restarted, so the event loop wakes up.
+pub fn install_shutdown_handler() -> Result<()> {
+    install_handler(libc::SIGTERM, on_shutdown_signal)?;
+    install_handler(libc::SIGINT, on_shutdown_signal)
+}
+
+/// Returns true if SIGTERM or SIGINT was received, or `request_shutdown` called.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Asks every event loop to shut down as if SIGTERM had been received, e.g.
+/// because the loop for another device failed.
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Arranges for SIGHUP to request a config reload from the event loop.
+pub fn install_reload_handler() -> Result<()> {
+    install_handler(libc::SIGHUP, on_reload_signal)
+}
@@ -0,0 +1,71 @@
+//! Engine state that survives a restart when `state_file` is configured.
+//!
+//! Only logical state is kept. Which keys are physically held is never
+//! persisted, since nothing is held across a restart.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistedState {
+    #[serde(default)]
+    pub active_mode: Option<String>,
+    /// The input chord of the `CapsWord` mapping that was on, by key name.
+    #[serde(default)]
+    pub caps_word: Option<Vec<String>>,
+    /// The modifiers of the `OneShot` mappings that were armed, by key name.
+    #[serde(default)]
+    pub one_shots: Vec<String>,
+}
+
+impl PersistedState {
+    /// Returns `None` if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+        };
+        let state = toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Writes via a temporary file and a rename, so an interrupted save
+    /// leaves the previous state intact.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = toml::to_string(self)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, text).with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("evremap-state-{}.toml", std::process::id()));
+        assert_eq!(PersistedState::load(&path).unwrap(), None);
+
+        let state = PersistedState {
+            active_mode: Some("nav".to_string()),
+            caps_word: Some(vec![
+                "KEY_LEFTSHIFT".to_string(),
+                "KEY_RIGHTSHIFT".to_string(),
+            ]),
+            one_shots: vec!["KEY_LEFTCTRL".to_string()],
+        };
+        state.save(&path).unwrap();
+        assert_eq!(PersistedState::load(&path).unwrap(), Some(state));
+
+        PersistedState::default()
+            .save(&path)
+            .unwrap();
+        assert_eq!(PersistedState::load(&path).unwrap(), Some(PersistedState::default()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
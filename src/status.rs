@@ -0,0 +1,54 @@
+//! Publishes the active mode at `status_path` for status bars to show.
+//!
+//! A regular file is rewritten with the mode name on each change, so a bar can
+//! poll it. A named pipe is written to instead, for bars that block on a read;
+//! when nobody has it open the update is dropped rather than stalling input.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+
+pub fn write_mode(path: &Path, mode: &str) -> std::io::Result<()> {
+    let is_fifo = std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_fifo());
+    let mut file = if is_fifo {
+        match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+        {
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => return Ok(()),
+            file => file?,
+        }
+    } else {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?
+    };
+    writeln!(file, "{mode}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_holds_only_the_latest_mode() {
+        let path = std::env::temp_dir().join(format!("evremap-status-{}", std::process::id()));
+        write_mode(&path, "navigation").unwrap();
+        write_mode(&path, "nav").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "nav\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fifo_without_reader_is_skipped() {
+        let path = std::env::temp_dir().join(format!("evremap-status-fifo-{}", std::process::id()));
+        let c_path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        write_mode(&path, "nav").unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}